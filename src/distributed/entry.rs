@@ -1,4 +1,4 @@
-use crate::storage::KV;
+use crate::storage::{Condition, KV};
 use std::fmt;
 use std::fmt::Formatter;
 use std::io::{Error, ErrorKind};
@@ -36,30 +36,114 @@ impl FromStr for LogEntry {
     }
 }
 
+// Keys and values are hex encoded, so they cannot clash with the separators of the command
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Works on the bytes, so input that is not ascii is rejected instead of split mid char
+fn decode_hex(s: &str) -> Result<Vec<u8>, Error> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid hex value {}", s));
+    if s.len() % 2 == 1 {
+        return Err(invalid());
+    }
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [high, low] if high.is_ascii_hexdigit() && low.is_ascii_hexdigit() => {
+                Ok((hex_digit(*high) << 4) | hex_digit(*low))
+            }
+            _ => Err(invalid()),
+        })
+        .collect()
+}
+
+fn hex_digit(c: u8) -> u8 {
+    (c as char).to_digit(16).unwrap() as u8
+}
+
 impl LogEntry {
     pub fn format_command(&self, cmd: &str, values: Vec<KV>) -> String {
         if cmd == "DELETE" {
-            return format!("{}:{}", cmd, values.first().unwrap().key);
+            return format!("{}:{}", cmd, encode_hex(&values.first().unwrap().key));
         }
 
         let mut entries = String::new();
         for v in values.clone() {
-            entries.push_str(&format!("{}.{};", v.key, v.value));
+            entries.push_str(&format!("{}.{};", encode_hex(&v.key), encode_hex(&v.value)));
         }
-        if values.len() > 0 {
+        if !values.is_empty() {
             entries.pop();
         }
         let encoded = format!("{}:{}", cmd, entries);
         encoded
     }
 
+    // A put whose keys expire at `expires_at`, in milliseconds since the unix epoch. The
+    // expiry is set once by the leader, so every node drops the keys at the same time
+    pub fn format_expiring_command(&self, cmd: &str, values: Vec<KV>, expires_at: u64) -> String {
+        format!("{}:{}", self.format_command(cmd, values), expires_at)
+    }
+
+    // The expiry of a command, if it has one
+    pub fn parse_expiry(&self, cmd: &str) -> Result<Option<u64>, Error> {
+        cmd.split(":")
+            .nth(2)
+            .map(|e| {
+                e.parse::<u64>().map_err(|_| {
+                    Error::new(ErrorKind::InvalidInput, format!("Invalid expiry {}", e))
+                })
+            })
+            .transpose()
+    }
+
+    // A put or delete applied only if the key meets the condition, e.g. "PUT IF VERSION 12".
+    // The condition is checked by every node as it applies the entry
+    pub fn format_conditional_command(
+        &self,
+        cmd: &str,
+        values: Vec<KV>,
+        condition: &Condition,
+    ) -> String {
+        let condition = match condition {
+            Condition::Absent => "ABSENT".to_string(),
+            Condition::Version(version) => format!("VERSION {}", version),
+            Condition::Value(value) => format!("VALUE {}", encode_hex(value)),
+        };
+        let command = self.format_command(cmd, values);
+        let (_, values) = command.split_once(":").unwrap();
+        format!("{} IF {}:{}", cmd, condition, values)
+    }
+
+    // The command without its condition, and the condition, if it has one
+    pub fn parse_condition(&self, command: &str) -> Result<(String, Option<Condition>), Error> {
+        let Some((cmd, condition)) = command.split_once(" IF ") else {
+            return Ok((command.to_string(), None));
+        };
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid condition {}", condition),
+            )
+        };
+        let condition = match condition.split_once(" ") {
+            None if condition == "ABSENT" => Condition::Absent,
+            Some(("VERSION", version)) => {
+                Condition::Version(version.parse().map_err(|_| invalid())?)
+            }
+            Some(("VALUE", value)) => Condition::Value(decode_hex(value)?),
+            _ => return Err(invalid()),
+        };
+        Ok((cmd.to_string(), Some(condition)))
+    }
+
     pub fn parse_command(&self, cmd: &str) -> Result<(String, Vec<KV>), Error> {
         let mut c = cmd.split(":");
         let mut f_values = Vec::new();
         if let (Some(command), Some(values)) = (c.next(), c.next()) {
-            if command == "DELETE" {
+            if command == "DELETE" || command.starts_with("DELETE IF ") {
                 f_values.push(KV {
-                    key: values.parse().unwrap(),
+                    key: decode_hex(values)?,
                     value: Default::default(),
                 });
                 return Ok((command.to_string(), f_values));
@@ -70,8 +154,8 @@ impl LogEntry {
                 let mut kv = v.split(".");
                 if let (Some(key), Some(val)) = (kv.next(), kv.next()) {
                     f_values.push(KV {
-                        key: key.parse().unwrap(),
-                        value: val.to_string(),
+                        key: decode_hex(key)?,
+                        value: decode_hex(val)?,
                     })
                 } else {
                     return Err(Error::new(
@@ -17,10 +17,7 @@ pub(crate) fn create_new_file(full_filename: &str) -> Result<bool, Error> {
 }
 
 pub(crate) fn append_to_file(full_filename: &str, content: Vec<(u64, &str)>) -> Result<(), Error> {
-    let mut file = OpenOptions::new()
-        .write(true)
-        .append(true)
-        .open(full_filename)?;
+    let mut file = OpenOptions::new().append(true).open(full_filename)?;
 
     file.seek(SeekFrom::End(0))?;
     for (term, entry) in content {
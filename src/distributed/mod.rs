@@ -1,16 +1,17 @@
 use crate::distributed::entry::LogEntry;
 use crate::distributed::node::{new_node, Leader, Node};
 use crate::distributed::rpc::new_rpc;
-use crate::storage::bit_cask::{new_bit_cask, BitCask};
-use crate::storage::{KVStorage, KV};
+use crate::storage::bit_cask::{open, BitCask, BitCaskOptions};
+use crate::storage::{Condition, KVIter, KVStorage, RangePage, RangeQuery, KV};
 use std::collections::HashMap;
 use std::io::{Error, ErrorKind};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 mod entry;
 mod logfile;
-pub(crate) mod node;
+pub mod node;
 mod rand;
-pub(crate) mod rpc;
+pub mod rpc;
 
 pub struct DistributedStorage {
     pub node: Node,
@@ -23,13 +24,14 @@ pub fn new_distributed_storage(
     port: u16,
     data_dir: &str,
     distributed: bool,
+    options: BitCaskOptions,
 ) -> Result<DistributedStorage, Error> {
     let node_id = port as u64;
     let nodes_map = HashMap::from([(4000, 4000), (5000, 5000), (6000, 6000)]);
     let nodes = vec![4000, 5000, 6000];
     let rpc = new_rpc(host, nodes_map)?;
 
-    let kv_storage = new_bit_cask(data_dir)?;
+    let kv_storage = open(data_dir, options)?;
     let node = new_node(node_id, rpc.clone(), nodes, kv_storage.clone())?;
 
     Ok(DistributedStorage {
@@ -40,20 +42,27 @@ pub fn new_distributed_storage(
 }
 
 impl DistributedStorage {
-    pub fn get(&self, key: usize) -> Result<String, Error> {
+    fn check_leader(&self) -> Result<(), Error> {
+        if !self.node.can_accept_requests() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Node is not a leader. Leader is node {}",
+                    self.node.get_leader()
+                ),
+            ));
+        }
+        Ok(())
+    }
+    pub fn get(&self, key: &[u8]) -> Result<Vec<u8>, Error> {
         self.storage.get(key)
     }
-    pub fn put(&mut self, key: usize, value: String) -> Result<(), Error> {
+    pub fn get_versioned(&self, key: &[u8]) -> Result<Option<(Vec<u8>, u64)>, Error> {
+        self.storage.get_versioned(key)
+    }
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Error> {
         if self.distributed {
-            if !self.node.can_accept_requests() {
-                return Err(Error::new(
-                    ErrorKind::InvalidInput,
-                    format!(
-                        "Node is not a leader. Leader is node {}",
-                        self.node.get_leader()
-                    ),
-                ));
-            }
+            self.check_leader()?;
             let le: LogEntry = Default::default();
             let request = le.format_command("PUT", vec![KV { key, value }]);
             self.node.add_request_to_log(request.as_str())?;
@@ -62,22 +71,14 @@ impl DistributedStorage {
         }
         Ok(())
     }
-    pub fn delete(&mut self, key: usize) -> Result<(), Error> {
+    pub fn delete(&mut self, key: &[u8]) -> Result<(), Error> {
         if self.distributed {
-            if !self.node.can_accept_requests() {
-                return Err(Error::new(
-                    ErrorKind::InvalidInput,
-                    format!(
-                        "Node is not a leader. Leader is node {}",
-                        self.node.get_leader()
-                    ),
-                ));
-            }
+            self.check_leader()?;
             let le: LogEntry = Default::default();
             let request = le.format_command(
                 "DELETE",
                 vec![KV {
-                    key,
+                    key: key.to_vec(),
                     value: Default::default(),
                 }],
             );
@@ -87,20 +88,20 @@ impl DistributedStorage {
         }
         Ok(())
     }
-    pub fn range(&self, start: usize, end: usize) -> Result<Vec<KV>, Error> {
+    pub fn range(&self, start: &[u8], end: &[u8]) -> Result<Vec<KV>, Error> {
         self.storage.range(start, end)
     }
+    pub fn scan(&self, query: &RangeQuery) -> Result<RangePage, Error> {
+        self.storage.scan(query)
+    }
+    pub fn iter(&self, query: &RangeQuery) -> KVIter {
+        self.storage.iter(query)
+    }
     pub fn batch_put(&mut self, kvs: Vec<KV>) -> Result<(), Error> {
         if self.distributed {
-            if !self.node.can_accept_requests() {
-                return Err(Error::new(
-                    ErrorKind::InvalidInput,
-                    format!(
-                        "Node is not a leader. Leader is node {}",
-                        self.node.get_leader()
-                    ),
-                ));
-            }
+            self.check_leader()?;
+            // checked before the entry is logged, every node would fail to apply it
+            self.storage.check_batch_size(batch_records(&kvs))?;
             let le: LogEntry = Default::default();
             let request = le.format_command("BATCH PUT", kvs);
             self.node.add_request_to_log(request.as_str())?;
@@ -109,13 +110,93 @@ impl DistributedStorage {
         }
         Ok(())
     }
+    // The condition is checked when the entry is applied, and the leader returns whether its
+    // copy met it. Followers apply the same entries in the same order, so theirs do too
+    pub fn put_if(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        condition: Condition,
+    ) -> Result<bool, Error> {
+        if self.distributed {
+            self.check_leader()?;
+            let le: LogEntry = Default::default();
+            let request = le.format_conditional_command("PUT", vec![KV { key, value }], &condition);
+            self.node.add_request_to_log(request.as_str())
+        } else {
+            self.storage.put_if(key, value, &condition)
+        }
+    }
+    pub fn delete_if(&mut self, key: &[u8], condition: Condition) -> Result<bool, Error> {
+        if self.distributed {
+            self.check_leader()?;
+            let le: LogEntry = Default::default();
+            let kv = KV {
+                key: key.to_vec(),
+                value: Default::default(),
+            };
+            let request = le.format_conditional_command("DELETE", vec![kv], &condition);
+            self.node.add_request_to_log(request.as_str())
+        } else {
+            self.storage.delete_if(key, &condition)
+        }
+    }
+    // The keys expire after `ttl`, measured from now on the leader
+    pub fn batch_put_with_ttl(&mut self, kvs: Vec<KV>, ttl: Duration) -> Result<(), Error> {
+        let expires_at = SystemTime::now() + ttl;
+        if self.distributed {
+            self.check_leader()?;
+            self.storage.check_batch_size(batch_records(&kvs))?;
+            let expires_at = expires_at
+                .duration_since(UNIX_EPOCH)
+                .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?
+                .as_millis() as u64;
+            let cmd = if kvs.len() == 1 { "PUT" } else { "BATCH PUT" };
+            let le: LogEntry = Default::default();
+            let request = le.format_expiring_command(cmd, kvs, expires_at);
+            self.node.add_request_to_log(request.as_str())?;
+        } else {
+            self.storage.batch_put_expiring_at(kvs, expires_at)?;
+        }
+        Ok(())
+    }
+}
+
+fn batch_records(kvs: &[KV]) -> impl ExactSizeIterator<Item = (&[u8], Option<&[u8]>)> {
+    kvs.iter()
+        .map(|kv| (kv.key.as_slice(), Some(kv.value.as_slice())))
 }
 
 #[cfg(test)]
 mod tests {
     use crate::distributed::entry::LogEntry;
-    use crate::distributed::node::{new_node, Follower};
+    use crate::distributed::logfile::get_log_filename;
+    use crate::distributed::node::{new_node, Follower, Leader, Node};
     use crate::distributed::rpc::{AppendEntriesRequest, VoteRequest};
+    use crate::storage::bit_cask::{open, BitCask, BitCaskOptions};
+    use crate::storage::{Condition, KVStorage, KV};
+    use std::fs;
+
+    // A node with a store of its own, in a cluster of `nodes`
+    fn node_with_storage(id: u64, nodes: Vec<u64>, data_dir: &str) -> (Node, BitCask) {
+        let _ = fs::remove_dir_all(data_dir);
+        let _ = fs::remove_file(get_log_filename(id));
+        let storage = open(data_dir, BitCaskOptions::new()).unwrap();
+        let node = new_node(id, Default::default(), nodes, storage.clone()).unwrap();
+        (node, storage)
+    }
+
+    fn put_entry(key: &[u8], value: &[u8], condition: Option<&Condition>) -> String {
+        let le: LogEntry = Default::default();
+        let values = vec![KV {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        }];
+        match condition {
+            Some(condition) => le.format_conditional_command("PUT", values, condition),
+            None => le.format_command("PUT", values),
+        }
+    }
 
     #[test]
     fn test_follower_insert_new_entries() {
@@ -259,6 +340,157 @@ mod tests {
         assert_eq!(l[0].entry, "second entry");
     }
 
+    #[test]
+    fn test_command_with_binary_keys_and_values() {
+        let le: LogEntry = Default::default();
+        let command = le.format_command(
+            "BATCH PUT",
+            vec![
+                KV {
+                    key: b"user:1;2.3".to_vec(),
+                    value: b"first.value;".to_vec(),
+                },
+                KV {
+                    key: vec![0, 255, 124],
+                    value: vec![0, 159, 146, 150],
+                },
+            ],
+        );
+        let (cmd, values) = le.parse_command(&command).unwrap();
+        assert_eq!("BATCH PUT", cmd);
+        assert_eq!(2, values.len());
+        assert_eq!(b"user:1;2.3".to_vec(), values[0].key);
+        assert_eq!(b"first.value;".to_vec(), values[0].value);
+        assert_eq!(vec![0, 255, 124], values[1].key);
+        assert_eq!(vec![0, 159, 146, 150], values[1].value);
+
+        let command = le.format_command("DELETE", values);
+        let (cmd, values) = le.parse_command(&command).unwrap();
+        assert_eq!("DELETE", cmd);
+        assert_eq!(b"user:1;2.3".to_vec(), values[0].key);
+
+        // malformed entries are rejected, not a reason to stop applying the log
+        for malformed in [
+            "DELETE:aé0",
+            "DELETE:éé",
+            "DELETE:+f",
+            "BATCH PUT:61.6",
+            "DELETE:zz",
+        ] {
+            assert!(le.parse_command(malformed).is_err());
+        }
+    }
+
+    #[test]
+    fn test_expiring_command() {
+        let le: LogEntry = Default::default();
+        let values = vec![KV {
+            key: b"session:1".to_vec(),
+            value: b"user-7".to_vec(),
+        }];
+        let command = le.format_expiring_command("PUT", values.clone(), 1_700_000_000_000);
+        let (cmd, parsed) = le.parse_command(&command).unwrap();
+        assert_eq!("PUT", cmd);
+        assert_eq!(b"session:1".to_vec(), parsed[0].key);
+        assert_eq!(b"user-7".to_vec(), parsed[0].value);
+        assert_eq!(Some(1_700_000_000_000), le.parse_expiry(&command).unwrap());
+
+        let command = le.format_command("PUT", values);
+        assert_eq!(None, le.parse_expiry(&command).unwrap());
+        assert!(le.parse_expiry("PUT:6b.76:soon").is_err());
+    }
+
+    #[test]
+    fn test_conditional_command() {
+        let le: LogEntry = Default::default();
+        let values = vec![KV {
+            key: b"lock:1".to_vec(),
+            value: b"owner-2".to_vec(),
+        }];
+        let conditions = vec![
+            Condition::Absent,
+            Condition::Version(42),
+            Condition::Value(b"owner-1".to_vec()),
+        ];
+        for condition in conditions {
+            let command = le.format_conditional_command("PUT", values.clone(), &condition);
+            let (cmd, parsed) = le.parse_command(&command).unwrap();
+            assert_eq!(b"owner-2".to_vec(), parsed[0].value);
+            assert_eq!(
+                ("PUT".to_string(), Some(condition.clone())),
+                le.parse_condition(&cmd).unwrap()
+            );
+            assert_eq!(None, le.parse_expiry(&command).unwrap());
+
+            let command = le.format_conditional_command("DELETE", parsed, &condition);
+            let (cmd, parsed) = le.parse_command(&command).unwrap();
+            assert_eq!(b"lock:1".to_vec(), parsed[0].key);
+            assert_eq!(
+                ("DELETE".to_string(), Some(condition)),
+                le.parse_condition(&cmd).unwrap()
+            );
+        }
+        assert_eq!(
+            ("PUT".to_string(), None),
+            le.parse_condition("PUT").unwrap()
+        );
+        assert!(le.parse_condition("PUT IF VERSION new").is_err());
+    }
+
+    #[test]
+    fn test_leader_reports_outcome_of_its_entry() {
+        let data_dir = "test-data-raft-outcome";
+        let (mut node, _) = node_with_storage(9, vec![9], data_dir);
+        assert!(node
+            .add_request_to_log(&put_entry(b"k", b"v1", None))
+            .unwrap());
+        let if_absent = put_entry(b"k", b"v2", Some(&Condition::Absent));
+        assert!(!node.add_request_to_log(&if_absent).unwrap());
+        let if_absent = put_entry(b"other", b"v2", Some(&Condition::Absent));
+        assert!(node.add_request_to_log(&if_absent).unwrap());
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_versions_match_across_nodes() {
+        let (mut first, first_storage) = node_with_storage(10, vec![10], "test-data-raft-a");
+        let (mut second, mut second_storage) = node_with_storage(11, vec![11], "test-data-raft-b");
+        // a write of its own moves the sequence numbers of the second node ahead
+        second_storage
+            .put(b"local".to_vec(), b"v".to_vec())
+            .unwrap();
+
+        // both follow the first leader
+        let req = || AppendEntriesRequest {
+            node: 0,
+            term: 1,
+            leader_id: 154,
+            prev_log_idx: 0,
+            entries: vec![LogEntry {
+                term: 1,
+                entry: put_entry(b"k", b"v1", None),
+                entry_idx: 1,
+            }],
+            prev_log_term: 0,
+            lead_commit: 1,
+        };
+        assert!(first.append_entries(req()).unwrap().1);
+        assert!(second.append_entries(req()).unwrap().1);
+        let (_, version) = first_storage.get_versioned(b"k").unwrap().unwrap();
+        assert_eq!(
+            Some((b"v1".to_vec(), version)),
+            second_storage.get_versioned(b"k").unwrap()
+        );
+
+        // a version read from the old leader holds once the second node leads, and only once
+        let if_version = put_entry(b"k", b"v2", Some(&Condition::Version(version)));
+        assert!(second.add_request_to_log(&if_version).unwrap());
+        assert!(!second.add_request_to_log(&if_version).unwrap());
+        assert_eq!(b"v2".to_vec(), second_storage.get(b"k").unwrap());
+        fs::remove_dir_all("test-data-raft-a").unwrap();
+        fs::remove_dir_all("test-data-raft-b").unwrap();
+    }
+
     #[test]
     fn test_follower_vote() {
         let mut node = new_node(
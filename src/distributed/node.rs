@@ -6,12 +6,15 @@ use crate::distributed::rand::get_timer_reset;
 use crate::distributed::rpc::{AppendEntriesRequest, HTTPNode, VoteRequest};
 use crate::storage::bit_cask::BitCask;
 use crate::storage::KVStorage;
-use std::cmp::max;
+use std::cmp::{max, min};
 use std::collections::HashMap;
 use std::io::{Error, ErrorKind};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, UNIX_EPOCH};
+
+// Bits of the sequence numbers left to the records of a log entry, below its log index
+const ENTRY_SEQ_BITS: u32 = 32;
 
 pub trait Follower {
     fn append_entries(&mut self, req: AppendEntriesRequest) -> Result<(u64, bool), Error>;
@@ -19,7 +22,8 @@ pub trait Follower {
 }
 
 pub trait Leader {
-    fn add_request_to_log(&mut self, req: &str) -> Result<(), Error>;
+    // Returns whether the request changed the store once applied
+    fn add_request_to_log(&mut self, req: &str) -> Result<bool, Error>;
     fn request_append_entries(&mut self, nodes: Vec<u64>, rpc: HTTPNode) -> Result<u64, Error>;
 }
 
@@ -27,6 +31,7 @@ trait Candidate {
     fn start_election(&mut self, nodes: Vec<u64>, rpc: HTTPNode) -> Result<bool, Error>;
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Eq, PartialEq)]
 pub enum State {
     FOLLOWER,
@@ -43,6 +48,9 @@ struct NodeState {
     last_applied: u64,
     election_timer: i64,
     leader_id: u64,
+    // Whether the entries that `add_request_to_log` waits for changed the store, by log index,
+    // set once they are applied
+    outcomes: HashMap<u64, Option<bool>>,
 
     // Leader state
     next_idx: HashMap<u64, u64>,
@@ -96,6 +104,7 @@ pub fn new_node(id: u64, rpc: HTTPNode, nodes: Vec<u64>, storage: BitCask) -> Re
         next_idx: match_idx.clone(),
         match_idx,
         leader_id: 0,
+        outcomes: HashMap::new(),
     }));
 
     let n = Node {
@@ -112,7 +121,7 @@ pub fn new_node(id: u64, rpc: HTTPNode, nodes: Vec<u64>, storage: BitCask) -> Re
         let mut state_lock = node.state.lock().unwrap();
 
         if state_lock.commit_idx > state_lock.last_applied {
-            let c_idx = state_lock.commit_idx.clone();
+            let c_idx = state_lock.commit_idx;
             drop(state_lock);
             let r = node.apply_log(c_idx);
             if r.is_err() {
@@ -123,16 +132,14 @@ pub fn new_node(id: u64, rpc: HTTPNode, nodes: Vec<u64>, storage: BitCask) -> Re
 
         match state_lock.state {
             State::FOLLOWER => {
-                state_lock.election_timer = state_lock.election_timer - 5;
+                state_lock.election_timer -= 5;
                 if state_lock.election_timer < 0 {
                     drop(state_lock);
                     println!("Election time out. Node {} is starting election", id);
                     let result = node.start_election(nodes.clone(), rpc.clone());
-                    if result.is_ok() {
-                        if result.unwrap() {
-                            println!("Newly elected leader node {} sending append entries", id);
-                            let _ = node.request_append_entries(nodes.clone(), rpc.clone());
-                        }
+                    if let Ok(true) = result {
+                        println!("Newly elected leader node {} sending append entries", id);
+                        let _ = node.request_append_entries(nodes.clone(), rpc.clone());
                     }
                 }
             }
@@ -149,35 +156,41 @@ pub fn new_node(id: u64, rpc: HTTPNode, nodes: Vec<u64>, storage: BitCask) -> Re
 }
 
 impl Node {
-    // Might be preferable to allow partial apply
+    // Might be preferable to allow partial apply.
+    // Applies the entries up to the log index `idx`, and records the outcome of the ones
+    // awaited by `add_request_to_log`
     fn apply_log(&mut self, idx: u64) -> Result<(), Error> {
         let mut state_lock = self.state.lock().unwrap();
+        let idx = min(idx, state_lock.log.len() as u64);
+        let first_idx = state_lock.last_applied;
+        if idx <= first_idx {
+            return Ok(());
+        }
         let log_file = get_log_filename(self.node_id);
         let mut entries: Vec<(u64, &str)> = Vec::new();
-        for i in &state_lock.log[(state_lock.last_applied as usize)..(idx as usize)] {
+        let mut outcomes = Vec::new();
+        for (log_idx, i) in
+            (first_idx + 1..).zip(&state_lock.log[(first_idx as usize)..(idx as usize)])
+        {
             entries.push((i.term, i.entry.as_str()));
-            let (cmd, values) = i.parse_command(i.entry.as_str())?;
-            match cmd.as_str() {
-                "BATCH PUT" => {
-                    self.storage.batch_put(values)?;
-                }
-                "PUT" => {
-                    let v = values.first().unwrap();
-                    self.storage.put(v.key, v.value.clone())?;
-                }
-                "DELETE" => {
-                    self.storage.delete(values.first().unwrap().key)?;
-                }
-                _ => println!("Command {} not found", cmd),
-            }
+            // the versions of the keys written are the sequence numbers of their records, so
+            // each entry starts from a number set by its log index, the same on every node
+            self.storage.skip_seqs_to(log_idx << ENTRY_SEQ_BITS);
+            outcomes.push((log_idx, apply_entry(&mut self.storage, i)?));
         }
 
         append_to_file(log_file.as_str(), entries)?;
         println!("Applied idx {}", idx);
         state_lock.last_applied = idx;
+        for (log_idx, applied) in outcomes {
+            if let Some(outcome) = state_lock.outcomes.get_mut(&log_idx) {
+                *outcome = Some(applied);
+            }
+        }
         Ok(())
     }
 
+    #[cfg(test)]
     pub(crate) fn get_log(&self) -> Vec<LogEntry> {
         self.state.lock().unwrap().log.clone()
     }
@@ -191,6 +204,46 @@ impl Node {
     }
 }
 
+// Returns whether the entry changed the store: false for a conditional write whose
+// condition did not hold
+fn apply_entry(storage: &mut BitCask, i: &LogEntry) -> Result<bool, Error> {
+    let (cmd, values) = i.parse_command(i.entry.as_str())?;
+    // every node checks the condition against its own copy, which has seen the same
+    // entries in the same order
+    let (cmd, condition) = i.parse_condition(&cmd)?;
+    if let Some(condition) = condition {
+        let v = values.first().unwrap();
+        return match cmd.as_str() {
+            "PUT" => storage.put_if(v.key.clone(), v.value.clone(), &condition),
+            "DELETE" => storage.delete_if(&v.key, &condition),
+            _ => {
+                println!("Command {} not found", cmd);
+                Ok(false)
+            }
+        };
+    }
+    // puts with a ttl carry the time the keys expire at, set by the leader
+    if let Some(expires_at) = i.parse_expiry(i.entry.as_str())? {
+        let expires_at = UNIX_EPOCH + Duration::from_millis(expires_at);
+        storage.batch_put_expiring_at(values, expires_at)?;
+        return Ok(true);
+    }
+    match cmd.as_str() {
+        "BATCH PUT" => {
+            storage.batch_put(values)?;
+        }
+        "PUT" => {
+            let v = values.first().unwrap();
+            storage.put(v.key.clone(), v.value.clone())?;
+        }
+        "DELETE" => {
+            storage.delete(&values.first().unwrap().key)?;
+        }
+        _ => println!("Command {} not found", cmd),
+    }
+    Ok(true)
+}
+
 impl Follower for Node {
     fn append_entries(&mut self, req: AppendEntriesRequest) -> Result<(u64, bool), Error> {
         let mut state_lock = self.state.lock().unwrap();
@@ -287,7 +340,7 @@ impl Candidate for Node {
         println!("Starting election for node {}", self.node_id);
 
         state_lock.state = State::CANDIDATE;
-        state_lock.current_term = state_lock.current_term + 1;
+        state_lock.current_term += 1;
         state_lock.election_timer = get_timer_reset(self.node_id);
         state_lock.voted_for = self.node_id;
 
@@ -296,12 +349,9 @@ impl Candidate for Node {
         let last_log = state_lock.log.last();
         let mut last_log_idx = 0;
         let mut last_log_term = 0;
-        match last_log {
-            Some(log_entry) => {
-                last_log_idx = log_entry.entry_idx;
-                last_log_term = log_entry.term;
-            }
-            None => {}
+        if let Some(log_entry) = last_log {
+            last_log_idx = log_entry.entry_idx;
+            last_log_term = log_entry.term;
         }
 
         let mut number_of_votes = 1;
@@ -351,7 +401,7 @@ impl Candidate for Node {
 }
 
 impl Leader for Node {
-    fn add_request_to_log(&mut self, req: &str) -> Result<(), Error> {
+    fn add_request_to_log(&mut self, req: &str) -> Result<bool, Error> {
         let mut state_lock = self.state.lock().unwrap();
         let last_idx = (state_lock.log.len() + 1) as u64;
         let current_term = state_lock.current_term;
@@ -362,24 +412,32 @@ impl Leader for Node {
             entry_idx: last_idx,
         });
 
-        state_lock.commit_idx = last_idx;
+        state_lock.outcomes.insert(last_idx, None);
         drop(state_lock);
-        let applied_counter =
-            self.request_append_entries(self.other_nodes.clone(), self.rpc.clone())?;
         let majority = ((self.other_nodes.len() / 2) + 1) as u64;
-        if applied_counter >= majority {
-            self.apply_log(last_idx)?;
-        } else {
-            // revert commit
-            let mut state_lock = self.state.lock().unwrap();
-            state_lock.commit_idx = current_term;
+        let replicated = self
+            .request_append_entries(self.other_nodes.clone(), self.rpc.clone())
+            .map(|applied_counter| applied_counter >= majority);
+        let mut state_lock = self.state.lock().unwrap();
+        if !matches!(replicated, Ok(true)) {
+            // the entry is not committed
+            state_lock.outcomes.remove(&last_idx);
+            replicated?;
             return Err(Error::new(
                 ErrorKind::NotFound,
                 "Nodes are inconsistent, command could not be applied".to_string(),
             ));
         }
+        state_lock.commit_idx = max(state_lock.commit_idx, last_idx);
+        drop(state_lock);
 
-        Ok(())
+        // the background loop may apply the entry first, its outcome is recorded either way
+        let result = self.apply_log(last_idx);
+        let outcome = self.state.lock().unwrap().outcomes.remove(&last_idx);
+        result?;
+        outcome
+            .flatten()
+            .ok_or_else(|| Error::other("Command was committed but not applied yet"))
     }
 
     fn request_append_entries(&mut self, nodes: Vec<u64>, rpc: HTTPNode) -> Result<u64, Error> {
@@ -391,8 +449,8 @@ impl Leader for Node {
                 continue;
             }
 
-            let next_idx = state_lock.next_idx.get(&n).unwrap().clone();
-            let match_idx = state_lock.match_idx.get(&n).unwrap().clone();
+            let next_idx = *state_lock.next_idx.get(&n).unwrap();
+            let match_idx = *state_lock.match_idx.get(&n).unwrap();
 
             let log_idx = state_lock.log.len() as u64;
             let mut prev_log_idx = 0;
@@ -25,7 +25,7 @@ impl fmt::Display for AppendEntriesRequest {
             self.node, self.term, self.leader_id, self.prev_log_idx, self.prev_log_term
         );
         for e in self.entries.clone() {
-            body = format!("{}+{}", body, e.to_string());
+            body = format!("{}+{}", body, e);
         }
         body = format!("{},{}", body, self.lead_commit);
 
@@ -57,10 +57,10 @@ impl FromStr for AppendEntriesRequest {
             parts.next(),
         ) {
             let mut parsed_entries = Vec::new();
-            if entries.len() > 0 {
+            if !entries.is_empty() {
                 let entries = entries.split('+');
                 for e in entries {
-                    if e.len() <= 0 {
+                    if e.is_empty() {
                         continue;
                     }
                     parsed_entries.push(LogEntry::from_str(e)?);
@@ -143,7 +143,7 @@ pub struct NodeResponse {
 }
 
 #[derive(Clone, Default)]
-pub(crate) struct HTTPNode {
+pub struct HTTPNode {
     host: String,
     nodes: HashMap<u64, u16>,
 }
@@ -166,7 +166,7 @@ impl HTTPNode {
             ));
         }
         let node_port = node_port.unwrap();
-        let mut stream = TcpStream::connect((self.host.as_str(), node_port.clone()))?;
+        let mut stream = TcpStream::connect((self.host.as_str(), *node_port))?;
 
         let body = req.to_string();
         // Send the HTTP POST request
@@ -194,7 +194,7 @@ impl HTTPNode {
             ));
         }
         let node_port = node_port.unwrap();
-        let mut stream = TcpStream::connect((self.host.as_str(), node_port.clone()))?;
+        let mut stream = TcpStream::connect((self.host.as_str(), *node_port))?;
 
         let body = req.to_string();
         let request = format!(
@@ -220,7 +220,7 @@ fn read_response(mut reader: BufReader<&TcpStream>) -> Result<NodeResponse, Erro
         .parse()
         .unwrap_or(0);
 
-    if content_length <= 0 {
+    if content_length == 0 {
         return Err(Error::new(ErrorKind::InvalidInput, "Content is empty"));
     }
 
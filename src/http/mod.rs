@@ -1,7 +1,8 @@
 use std::collections::HashMap;
 use std::io::Read;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Error, Write};
 use std::net::TcpStream;
+use std::str;
 
 pub fn read_headers(reader: &mut BufReader<&TcpStream>) -> HashMap<String, String> {
     let mut headers = HashMap::new();
@@ -16,3 +17,107 @@ pub fn read_headers(reader: &mut BufReader<&TcpStream>) -> HashMap<String, Strin
     }
     headers
 }
+
+// Decodes %XX escapes, so keys can hold any byte. Invalid escapes are kept as they are
+pub fn percent_decode(bytes: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(b) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                decoded.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    decoded
+}
+
+// Escapes every byte but unreserved URL characters, for keys sent back in a response
+pub fn percent_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len());
+    for b in bytes {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(b) {
+            encoded.push(*b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+// Bytes buffered before a chunk is sent
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Sends a response body whose length is not known up front as it is written, in chunks
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    // Writes the headers of the response
+    pub fn new(mut inner: W) -> Result<Self, Error> {
+        inner.write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n")?;
+        Ok(ChunkedWriter {
+            inner,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        })
+    }
+
+    // Sends what is left of the body, and its end
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.flush()?;
+        self.inner.write_all(b"0\r\n\r\n")?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, bytes: &[u8]) -> Result<usize, Error> {
+        self.buf.extend_from_slice(bytes);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        // an empty chunk would end the body
+        if !self.buf.is_empty() {
+            write!(self.inner, "{:X}\r\n", self.buf.len())?;
+            self.inner.write_all(&self.buf)?;
+            self.inner.write_all(b"\r\n")?;
+            self.buf.clear();
+        }
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunked_writer() {
+        let mut response = Vec::new();
+        let mut body = ChunkedWriter::new(&mut response).unwrap();
+        body.write_all(b"first").unwrap();
+        body.flush().unwrap();
+        body.flush().unwrap();
+        body.write_all(&[b'x'; CHUNK_SIZE]).unwrap();
+        body.write_all(b"last").unwrap();
+        body.finish().unwrap();
+
+        let mut expected =
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nfirst\r\n10000\r\n"
+                .to_vec();
+        expected.extend_from_slice(&[b'x'; CHUNK_SIZE]);
+        expected.extend_from_slice(b"\r\n4\r\nlast\r\n0\r\n\r\n");
+        assert_eq!(expected, response);
+    }
+}
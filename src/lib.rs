@@ -0,0 +1,3 @@
+pub mod distributed;
+pub mod http;
+pub mod storage;
@@ -1,18 +1,17 @@
 // #![feature(test)]
 // extern crate test;
-use crate::distributed::node::Follower;
-mod distributed;
-mod http;
-mod storage;
-
-use crate::distributed::rpc::{AppendEntriesRequest, VoteRequest};
-use crate::distributed::{new_distributed_storage, DistributedStorage};
-use crate::http::read_headers;
-use crate::storage::KV;
+use key_value_storage::distributed::node::Follower;
+use key_value_storage::distributed::rpc::{AppendEntriesRequest, VoteRequest};
+use key_value_storage::distributed::{new_distributed_storage, DistributedStorage};
+use key_value_storage::http::{percent_decode, percent_encode, read_headers, ChunkedWriter};
+use key_value_storage::storage::bit_cask::BitCaskOptions;
+use key_value_storage::storage::{Condition, RangeQuery, KV};
 use std::collections::HashMap;
 use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
 use std::net::{TcpListener, TcpStream};
+use std::ops::Bound::{Excluded, Included, Unbounded};
 use std::str::FromStr;
+use std::time::Duration;
 use std::{env, str};
 
 const DEFAULT_PORT: &str = "4000";
@@ -24,6 +23,7 @@ fn main() {
     let mut port = DEFAULT_PORT;
     let mut data_dir = DEFAULT_DATA_DIR;
     let mut distributed = true;
+    let mut options = BitCaskOptions::new();
 
     for i in 0..args.len() {
         if args[i] == "port" && i + 1 < args.len() {
@@ -34,17 +34,25 @@ fn main() {
         }
 
         if args[i] == "distributed" && i + 1 < args.len() {
-            distributed = (&args[i + 1]).parse().unwrap();
+            distributed = args[i + 1].parse().unwrap();
+        }
+
+        if args[i] == "sync" && i + 1 < args.len() {
+            options = options.sync_policy(args[i + 1].parse().unwrap());
+        }
+
+        if args[i] == "max-batch-size" && i + 1 < args.len() {
+            options = options.max_batch_size(args[i + 1].parse().unwrap());
         }
     }
 
     let endpoint = format!("{}:{}", HOST, port);
     let listener =
-        TcpListener::bind(endpoint).expect(format!("Failed to bind to port {}", port).as_str());
+        TcpListener::bind(endpoint).unwrap_or_else(|_| panic!("Failed to bind to port {}", port));
     println!("HTTP server running on {}...", port);
 
     let distributed_storage =
-        new_distributed_storage(HOST, port.parse().unwrap(), data_dir, distributed);
+        new_distributed_storage(HOST, port.parse().unwrap(), data_dir, distributed, options);
     if let Err(e) = distributed_storage {
         println!("Failed to initialize distributed storage: {}", e);
         return;
@@ -70,7 +78,7 @@ fn handle_client(mut stream: TcpStream, distributed_storage: &mut DistributedSto
         return;
     }
 
-    let request_parts: Vec<&str> = request_line.trim().split_whitespace().collect();
+    let request_parts: Vec<&str> = request_line.split_whitespace().collect();
     if request_parts.len() < 3 {
         return;
     }
@@ -80,11 +88,11 @@ fn handle_client(mut stream: TcpStream, distributed_storage: &mut DistributedSto
     let (route, query_params) = parse_path(path);
 
     let response = match (method, route) {
-        ("GET", "/") => get(query_params, distributed_storage),
+        ("GET", "/") => get(query_params, distributed_storage, &stream),
         ("POST", "/append-entries") => {
             let result = read_append_entries_request(reader);
             let s = match result {
-                Err(e) => format_response(format!("Failed to read response: {}", e.to_string())),
+                Err(e) => format_response(format!("Failed to read response: {}", e)),
                 Ok((_, v)) => {
                     let r = distributed_storage.node.append_entries(v);
                     match r {
@@ -98,7 +106,7 @@ fn handle_client(mut stream: TcpStream, distributed_storage: &mut DistributedSto
         ("POST", "/request-vote") => {
             let result = read_vote_request(reader);
             let s = match result {
-                Err(e) => format_response(format!("Failed to read response: {}", e.to_string())),
+                Err(e) => format_response(format!("Failed to read response: {}", e)),
                 Ok((_, v)) => {
                     let r = distributed_storage.node.vote(v);
                     match r {
@@ -110,14 +118,26 @@ fn handle_client(mut stream: TcpStream, distributed_storage: &mut DistributedSto
             s
         }
         ("POST", "/") => {
-            let (_, body) = read_kv_request(reader);
-            put(body, distributed_storage)
+            let key = query_params
+                .get("key")
+                .map(|k| percent_decode(k.as_bytes()));
+            let (_, body) = read_kv_request(reader, key);
+            match (parse_ttl(&query_params), parse_condition(&query_params)) {
+                (Err(e), _) | (_, Err(e)) => {
+                    format_response(format!("Failed to put keys. Err: {}", e))
+                }
+                (Ok(Some(_)), Ok(Some(_))) => {
+                    format_response("Failed to put key. Err: a conditional put has no ttl")
+                }
+                (Ok(_), Ok(Some(condition))) => put_if(body, condition, distributed_storage),
+                (Ok(ttl), Ok(None)) => put(body, ttl, distributed_storage),
+            }
         }
         ("DELETE", "/") => delete(query_params, distributed_storage),
         _ => default_response(),
     };
 
-    stream.write_all(response.as_bytes()).unwrap();
+    stream.write_all(&response).unwrap();
 }
 
 // Parses a typical URL path (e.g.: /path?arg1=val1&arg2=val2)
@@ -127,17 +147,55 @@ fn parse_path(path: &str) -> (&str, HashMap<String, String>) {
     let mut query_params = HashMap::new();
 
     if let Some(query) = parts.next() {
-        for pair in query.split('&') {
-            if let Some((key, value)) = pair.split_once('=') {
-                query_params.insert(key.to_string(), value.to_string());
-            }
+        // a param without a value, like `if_absent`, is kept with an empty one
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            query_params.insert(key.to_string(), value.to_string());
         }
     }
 
     (route, query_params)
 }
 
-fn read_kv_request(mut reader: BufReader<&TcpStream>) -> (HashMap<String, String>, Vec<KV>) {
+// `ttl` is in seconds
+fn parse_ttl(query_params: &HashMap<String, String>) -> Result<Option<Duration>, Error> {
+    query_params
+        .get("ttl")
+        .map(|ttl| {
+            ttl.parse()
+                .map(Duration::from_secs)
+                .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("Invalid ttl {}", ttl)))
+        })
+        .transpose()
+}
+
+// `if_absent`, `if_version=<version>` or `if_value=<value>` make a put or a delete of a single
+// key conditional
+fn parse_condition(query_params: &HashMap<String, String>) -> Result<Option<Condition>, Error> {
+    if query_params.contains_key("if_absent") {
+        return Ok(Some(Condition::Absent));
+    }
+    if let Some(version) = query_params.get("if_version") {
+        return version
+            .parse()
+            .map(|v| Some(Condition::Version(v)))
+            .map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid version {}", version),
+                )
+            });
+    }
+    Ok(query_params
+        .get("if_value")
+        .map(|v| Condition::Value(percent_decode(v.as_bytes()))))
+}
+
+// With a key, the whole body is its value. Otherwise the body has one key,value pair per line
+fn read_kv_request(
+    mut reader: BufReader<&TcpStream>,
+    key: Option<Vec<u8>>,
+) -> (HashMap<String, String>, Vec<KV>) {
     let headers = read_headers(&mut reader);
     let content_length = headers
         .get("content-length")
@@ -149,13 +207,17 @@ fn read_kv_request(mut reader: BufReader<&TcpStream>) -> (HashMap<String, String
     if content_length > 0 {
         let mut buffer = vec![0; content_length];
         if reader.read_exact(&mut buffer).is_ok() {
-            let content = String::from_utf8_lossy(&buffer);
-            for line in content.lines() {
-                let mut parts = line.split(',');
+            if let Some(key) = key {
+                body_map.push(KV { key, value: buffer });
+                return (headers, body_map);
+            }
+            for line in buffer.split(|b| *b == b'\n') {
+                let line = line.strip_suffix(b"\r").unwrap_or(line);
+                let mut parts = line.splitn(2, |b| *b == b',');
                 if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
                     body_map.push(KV {
-                        key: key.parse().unwrap(),
-                        value: value.to_string(),
+                        key: percent_decode(key),
+                        value: value.to_vec(),
                     });
                 }
             }
@@ -175,7 +237,7 @@ fn read_append_entries_request(
         .parse()
         .unwrap_or(0);
 
-    if content_length <= 0 {
+    if content_length == 0 {
         return Err(Error::new(ErrorKind::InvalidInput, "Content is empty"));
     }
 
@@ -201,7 +263,7 @@ fn read_vote_request(
         .parse()
         .unwrap_or(0);
 
-    if content_length <= 0 {
+    if content_length == 0 {
         return Err(Error::new(ErrorKind::InvalidInput, "Content is empty"));
     }
 
@@ -218,92 +280,212 @@ fn read_vote_request(
 }
 
 // Basic HTTP response
-fn format_response(body: String) -> String {
-    format!(
-        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
-        body.len(),
-        body
-    )
+fn format_response(body: impl Into<Vec<u8>>) -> Vec<u8> {
+    let body = body.into();
+    let mut response =
+        format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+    response.extend_from_slice(&body);
+    response
 }
 
-fn default_response() -> String {
+fn default_response() -> Vec<u8> {
     let get_request_instructions = "curl --location 'http://localhost:4000?key=1'";
     let get_range_req_instructions =
         "curl --location 'http://localhost:4000?start_key=1&end_key=10'";
-    let put_request_instructions = "curl --location 'http://localhost:4000/' --header 'Content-Type: text/plain' --data 'key:1,value:2000'";
+    let get_range_page_instructions =
+        "curl --location 'http://localhost:4000?start_after=1&limit=100&reverse=true&after=5'";
+    let put_request_instructions = "curl --location 'http://localhost:4000/?key=1' --header 'Content-Type: application/octet-stream' --data-binary '@value.bin'";
+    let ttl_put_request_instructions =
+        "curl --location 'http://localhost:4000/?key=1&ttl=60' --data-binary '@value.bin'";
     let bulk_put_req_instructions = "curl --location 'http://localhost:4000' --header 'Content-Type: text/plain' --data 'key:1,value:2000\nkey:2,value:5000\nkey:5,value:4000\nkey:11,value:502'";
     let delete_request_instructions =
         "curl --location --request DELETE 'http://localhost:4000?key=1'";
+    let get_version_instructions = "curl --location 'http://localhost:4000?key=1&version=true'";
+    let conditional_put_instructions =
+        "curl --location 'http://localhost:4000/?key=1&if_version=12' --data-binary '@value.bin'";
     format_response(format!(
-        "Usage:\nREAD: {}\nREAD KEY RANGE: {}\nPUT: {}\nBATCH PUT: {}\nDELETE: {}\n",
+        "Usage:\nREAD: {}\nREAD KEY RANGE: {}\nREAD KEY RANGE PAGE (start_key or start_after, end_key or end_before): {}\nPUT: {}\nPUT WITH TTL (seconds, also for BATCH PUT): {}\nBATCH PUT: {}\nDELETE: {}\nREAD VERSION: {}\nCONDITIONAL PUT (if_version, if_absent or if_value, also for DELETE): {}\n",
         get_request_instructions,
         get_range_req_instructions,
+        get_range_page_instructions,
         put_request_instructions,
+        ttl_put_request_instructions,
         bulk_put_req_instructions,
-        delete_request_instructions
+        delete_request_instructions,
+        get_version_instructions,
+        conditional_put_instructions
     ))
 }
 
-fn get(query_params: HashMap<String, String>, storage: &DistributedStorage) -> String {
+fn get(
+    query_params: HashMap<String, String>,
+    storage: &DistributedStorage,
+    stream: &TcpStream,
+) -> Vec<u8> {
     let key = query_params.get("key").cloned();
-    if let Some(key) = key {
-        let result = storage.get(key.parse().unwrap());
+    if let (Some(key), true) = (&key, query_params.contains_key("version")) {
+        let result = storage.get_versioned(&percent_decode(key.as_bytes()));
         return match result {
-            Err(result) => {
-                format_response(format!("Failed to read response: {}", result.to_string()))
-            }
-            Ok(result) => format_response(format!("Value: {}", result)),
+            Err(result) => format_response(format!("Failed to read response: {}", result)),
+            Ok(Some((_, version))) => format_response(version.to_string()),
+            Ok(None) => format_response("Key not found"),
         };
     }
-
-    let start_key = query_params.get("start_key").cloned();
-    let end_key = query_params.get("end_key").cloned();
-    if start_key.is_some() && end_key.is_some() {
-        let result = storage.range(
-            start_key.unwrap().parse().unwrap(),
-            end_key.unwrap().parse().unwrap(),
-        );
+    if let Some(key) = key {
+        let result = storage.get(&percent_decode(key.as_bytes()));
         return match result {
-            Err(result) => format_response(format!("Failed to read range: {}", result.to_string())),
-            Ok(result) => format_response(format!("Value: {:?}", result)),
+            Err(result) => format_response(format!("Failed to read response: {}", result)),
+            // the value is sent as is, so binary values arrive unchanged
+            Ok(result) => format_response(result),
         };
     }
+
+    let query = match parse_range_query(&query_params) {
+        Err(e) => return format_response(format!("Failed to read range: {}", e)),
+        Ok(query) => query,
+    };
+    if let Some(query) = query {
+        // the range is sent as it is read, an empty response means it was sent
+        let limit = query_params.get("limit").and_then(|l| l.parse().ok());
+        if let Err(e) = stream_range(stream, &query, limit, storage) {
+            println!("Failed to send range: {}", e);
+        }
+        return vec![];
+    }
     default_response()
 }
 
-fn put(body: Vec<KV>, storage: &mut DistributedStorage) -> String {
+// Keys are written out as the storage reads them, so the range is never held whole. Each key
+// is a `key,value` line, both percent-encoded so that any byte arrives unchanged. A range that
+// reaches the limit ends with the cursor of its last key
+fn stream_range(
+    stream: &TcpStream,
+    query: &RangeQuery,
+    limit: Option<usize>,
+    storage: &DistributedStorage,
+) -> Result<(), Error> {
+    let mut body = ChunkedWriter::new(stream)?;
+    let mut count = 0;
+    let mut last = None;
+    for kv in storage.iter(query) {
+        let kv = match kv {
+            Ok(kv) => kv,
+            // the response has started, so the error ends it
+            Err(e) => {
+                writeln!(body, "Failed to read range: {}", e)?;
+                return body.finish();
+            }
+        };
+        writeln!(
+            body,
+            "{},{}",
+            percent_encode(&kv.key),
+            percent_encode(&kv.value)
+        )?;
+        count += 1;
+        last = Some(kv.key);
+    }
+    if let (Some(last), true) = (last, Some(count) == limit) {
+        writeln!(body, "Cursor: {}", percent_encode(&last))?;
+    }
+    body.finish()
+}
+
+// A range read has a start (`start_key`, or `start_after` to leave it out), an end (`end_key`,
+// or `end_before`), or both. A missing bound leaves the range open on that side.
+// `limit`, `reverse=true` and `after=<cursor>`, with the cursor of the previous page, are
+// optional
+fn parse_range_query(query_params: &HashMap<String, String>) -> Result<Option<RangeQuery>, Error> {
+    let bound = |included: &str, excluded: &str| match (
+        query_params.get(included),
+        query_params.get(excluded),
+    ) {
+        (Some(key), _) => Included(percent_decode(key.as_bytes())),
+        (None, Some(key)) => Excluded(percent_decode(key.as_bytes())),
+        (None, None) => Unbounded,
+    };
+    let start = bound("start_key", "start_after");
+    let end = bound("end_key", "end_before");
+    if start == Unbounded && end == Unbounded {
+        return Ok(None);
+    }
+
+    let mut query = RangeQuery::new(start, end)
+        .reverse(query_params.get("reverse").is_some_and(|r| r == "true"));
+    if let Some(limit) = query_params.get("limit") {
+        let limit = limit
+            .parse()
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("Invalid limit {}", limit)))?;
+        query = query.limit(limit);
+    }
+    if let Some(cursor) = query_params.get("after") {
+        query = query.after(percent_decode(cursor.as_bytes()));
+    }
+    Ok(Some(query))
+}
+
+fn put(body: Vec<KV>, ttl: Option<Duration>, storage: &mut DistributedStorage) -> Vec<u8> {
     println!("Received: {:?}", body);
-    if body.len() == 0 {
+    if body.is_empty() {
         return default_response();
     }
 
+    if let Some(ttl) = ttl {
+        let result = storage.batch_put_with_ttl(body, ttl);
+        return match result {
+            Err(result) => format_response(format!("Failed to put keys. Err: {}", result)),
+            Ok(()) => format_response("Keys saved".to_string()),
+        };
+    }
+
     if body.len() == 1 {
         let f = body.first().cloned().unwrap();
         let result = storage.put(f.key, f.value);
         return match result {
-            Err(result) => {
-                format_response(format!("Failed to put key. Err {}", result.to_string()))
-            }
+            Err(result) => format_response(format!("Failed to put key. Err {}", result)),
             Ok(()) => format_response("Key saved".to_string()),
         };
     }
 
     let result = storage.batch_put(body);
     match result {
-        Err(result) => format_response(format!(
-            "Failed to batch put keys. Err: {}",
-            result.to_string()
-        )),
+        Err(result) => format_response(format!("Failed to batch put keys. Err: {}", result)),
         Ok(()) => format_response("Keys saved".to_string()),
     }
 }
 
-fn delete(query_params: HashMap<String, String>, storage: &mut DistributedStorage) -> String {
+fn put_if(body: Vec<KV>, condition: Condition, storage: &mut DistributedStorage) -> Vec<u8> {
+    println!("Received: {:?} if {:?}", body, condition);
+    if body.len() != 1 {
+        return format_response("Failed to put key. Err: a conditional put takes a single key");
+    }
+
+    let f = body.into_iter().next().unwrap();
+    match storage.put_if(f.key, f.value, condition) {
+        Err(result) => format_response(format!("Failed to put key. Err {}", result)),
+        Ok(true) => format_response("Key saved"),
+        Ok(false) => format_response("Condition not met, key not saved"),
+    }
+}
+
+fn delete(query_params: HashMap<String, String>, storage: &mut DistributedStorage) -> Vec<u8> {
     let key = query_params.get("key").cloned();
     if let Some(key) = key {
-        let result = storage.delete(key.parse().unwrap());
+        let condition = match parse_condition(&query_params) {
+            Err(e) => return format_response(format!("Failed to delete: {}", e)),
+            Ok(condition) => condition,
+        };
+        if let Some(condition) = condition {
+            let result = storage.delete_if(&percent_decode(key.as_bytes()), condition);
+            return match result {
+                Err(result) => format_response(format!("Failed to delete: {}", result)),
+                Ok(true) => format_response("Key deleted"),
+                Ok(false) => format_response("Condition not met, key not deleted"),
+            };
+        }
+        let result = storage.delete(&percent_decode(key.as_bytes()));
         return match result {
-            Err(result) => format_response(format!("Failed to delete: {}", result.to_string())),
+            Err(result) => format_response(format!("Failed to delete: {}", result)),
             Ok(()) => format_response("Key deleted".to_string()),
         };
     }
@@ -320,6 +502,125 @@ fn delete(query_params: HashMap<String, String>, storage: &mut DistributedStorag
 //         let storage = new_bit_cask("test-data");
 //         assert!(storage.is_ok());
 //         let mut storage = storage.unwrap();
-//         b.iter(|| storage.batch_put(vec![KV{ key: 1, value: "123".to_string() }]));
+//         b.iter(|| storage.batch_put(vec![KV{ key: b"1".to_vec(), value: "123".to_string() }]));
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::thread;
+
+    // Sends the raw request to `handle_client` and returns the raw response
+    fn request(storage: &mut DistributedStorage, request: &str) -> String {
+        let listener = TcpListener::bind((HOST, 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        let request = request.to_string();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).unwrap();
+            response
+        });
+        let (stream, _) = listener.accept().unwrap();
+        handle_client(stream, storage);
+        String::from_utf8(client.join().unwrap()).unwrap()
+    }
+
+    // The body of a response, put back together if it was sent in chunks
+    fn body(response: &str) -> String {
+        let (headers, mut rest) = response.split_once("\r\n\r\n").unwrap();
+        if !headers.contains("Transfer-Encoding: chunked") {
+            return rest.to_string();
+        }
+        let mut body = String::new();
+        loop {
+            let (size, chunk) = rest.split_once("\r\n").unwrap();
+            let size = usize::from_str_radix(size, 16).unwrap();
+            if size == 0 {
+                return body;
+            }
+            body.push_str(&chunk[..size]);
+            rest = &chunk[size + 2..];
+        }
+    }
+
+    fn local_storage(data_dir: &str, port: u16) -> DistributedStorage {
+        let _ = fs::remove_dir_all(data_dir);
+        new_distributed_storage(HOST, port, data_dir, false, BitCaskOptions::new()).unwrap()
+    }
+
+    #[test]
+    fn test_range_with_binary_values() {
+        let data_dir = "test-data-http-range";
+        let mut storage = local_storage(data_dir, 4901);
+        let value = "\u{0}\u{ff}a,b\nc";
+        storage
+            .put(b"k1".to_vec(), value.as_bytes().to_vec())
+            .unwrap();
+        storage.put(b"k/2".to_vec(), vec![0, 159, 146]).unwrap();
+
+        let response = request(&mut storage, "GET /?start_key=k&limit=2 HTTP/1.1\r\n\r\n");
+        assert_eq!(
+            "k%2F2,%00%9F%92\nk1,%00%C3%BFa%2Cb%0Ac\nCursor: k1\n",
+            body(&response)
+        );
+        assert_eq!(value.as_bytes(), percent_decode(b"%00%C3%BFa%2Cb%0Ac"));
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_conditional_put_on_existing_key() {
+        let data_dir = "test-data-http-conditional";
+        let mut storage = local_storage(data_dir, 4902);
+        storage.put(b"k".to_vec(), b"v1".to_vec()).unwrap();
+
+        // `if_absent` has no value, and still makes the put and the delete conditional
+        let put = "POST /?key=k&if_absent HTTP/1.1\r\nContent-Length: 2\r\n\r\nv2";
+        let response = request(&mut storage, put);
+        assert_eq!("Condition not met, key not saved", body(&response));
+        let response = request(&mut storage, "DELETE /?key=k&if_absent HTTP/1.1\r\n\r\n");
+        assert_eq!("Condition not met, key not deleted", body(&response));
+        assert_eq!(b"v1".to_vec(), storage.get(b"k").unwrap());
+
+        let put = "POST /?key=new&if_absent HTTP/1.1\r\nContent-Length: 2\r\n\r\nv2";
+        let response = request(&mut storage, put);
+        assert_eq!("Key saved", body(&response));
+        assert_eq!(b"v2".to_vec(), storage.get(b"new").unwrap());
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_batch_put_over_max_batch_size() {
+        let data_dir = "test-data-http-max-batch-size";
+        let _ = fs::remove_dir_all(data_dir);
+        let options = BitCaskOptions::new().max_batch_size(200);
+        let mut storage = new_distributed_storage(HOST, 4903, data_dir, false, options).unwrap();
+
+        let batch = "key:1,value:2000\nkey:2,value:5000";
+        let put = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            batch.len(),
+            batch
+        );
+        let response = request(&mut storage, &put);
+        assert_eq!("Keys saved", body(&response));
+
+        // rejected whole
+        let batch = "key:3,value:2000\nkey:4,value:5000\nkey:5,value:4000";
+        let put = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            batch.len(),
+            batch
+        );
+        let response = request(&mut storage, &put);
+        assert_eq!(
+            "Failed to batch put keys. Err: Batch of 225 bytes is larger than the maximum of 200 bytes",
+            body(&response)
+        );
+        assert!(storage.get(b"3").unwrap().is_empty());
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
@@ -1,15 +1,23 @@
 #[cfg(test)]
 mod tests {
-    use crate::storage::bit_cask::new_bit_cask;
-    use crate::storage::{KVStorage, KV};
+    use crate::storage::bit_cask::{
+        new_bit_cask, open, BitCaskOptions, CompactionThresholds, KeyIndex, SyncPolicy, WriteBatch,
+    };
+    use crate::storage::manifest::Manifest;
+    use crate::storage::{Condition, KVStorage, RangeQuery, KV};
+    use std::collections::{HashMap, HashSet};
+    use std::fs::OpenOptions;
+    use std::io::{Error, ErrorKind, Seek, SeekFrom, Write};
+    use std::ops::Bound::{Excluded, Included, Unbounded};
     use std::path::Path;
-    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use std::sync::{Arc, Barrier};
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
     use std::{fs, thread};
 
     const DATA_DIR: &str = "test-data";
 
-    fn clear_data() {
-        let path = Path::new(DATA_DIR);
+    fn clear_data(data_dir: &str) {
+        let path = Path::new(data_dir);
         if path.exists() {
             fs::remove_dir_all(path).expect("Failed to remove test directory");
         }
@@ -17,69 +25,1454 @@ mod tests {
 
     #[test]
     fn insert_retrieve_test() {
-        clear_data();
-        let storage = new_bit_cask(DATA_DIR);
+        let data_dir = "test-data-insert-retrieve";
+        clear_data(data_dir);
+        let storage = new_bit_cask(data_dir);
         assert!(storage.is_ok());
         let mut storage = storage.unwrap();
-        let put_result = storage.put(123, "my-value".to_string());
+        let put_result = storage.put(b"123".to_vec(), b"my-value".to_vec());
         assert!(put_result.is_ok());
-        let get_result = storage.get(123);
+        let get_result = storage.get(b"123");
         assert!(get_result.is_ok());
-        assert_eq!("my-value", get_result.unwrap());
+        assert_eq!(b"my-value".to_vec(), get_result.unwrap());
     }
 
     #[test]
     fn bulk_insert_retrieve_test() {
-        clear_data();
-        let storage = new_bit_cask(DATA_DIR);
+        let data_dir = "test-data-bulk-insert-retrieve";
+        clear_data(data_dir);
+        let storage = new_bit_cask(data_dir);
         assert!(storage.is_ok());
         let mut storage = storage.unwrap();
         let put_result = storage.batch_put(vec![
             KV {
-                key: 456,
-                value: "456 my value".to_string(),
+                key: b"456".to_vec(),
+                value: b"456 my value".to_vec(),
             },
             KV {
-                key: 789,
-                value: "789 my value".to_string(),
+                key: b"789".to_vec(),
+                value: b"789 my value".to_vec(),
             },
             KV {
-                key: 1111,
-                value: "1111 my value".to_string(),
+                key: b"1111".to_vec(),
+                value: b"1111 my value".to_vec(),
             },
         ]);
         assert!(put_result.is_ok());
-        let get_result = storage.range(400, 900);
+        let get_result = storage.range(b"400", b"900");
         assert!(get_result.is_ok());
         let r = get_result.unwrap();
         assert_eq!(2, r.len());
         let f = r.first().unwrap();
-        assert_eq!(456, f.key);
-        assert_eq!("456 my value", f.value);
+        assert_eq!(b"456".to_vec(), f.key);
+        assert_eq!(b"456 my value".to_vec(), f.value);
         let s = r.last().unwrap();
-        assert_eq!(789, s.key);
-        assert_eq!("789 my value", s.value);
+        assert_eq!(b"789".to_vec(), s.key);
+        assert_eq!(b"789 my value".to_vec(), s.value);
     }
 
     #[test]
-    fn timing_bulk_insert() {
-        // 1_000_000_000 exceeds memory available
-        // 1_000_000 takes about 4.60 seconds
-        // benchmark shows around 3500 ns/iteration
-        let record_count = 1_000_000;
+    fn binary_keys_and_values_test() {
+        let data_dir = "test-data-binary";
+        let _ = fs::remove_dir_all(data_dir);
+        let mut storage = new_bit_cask(data_dir).unwrap();
+        storage.put(b"user/10".to_vec(), b"ten".to_vec()).unwrap();
+        storage.put(b"user/9".to_vec(), b"nine".to_vec()).unwrap();
+        // not valid UTF-8
+        storage.put(vec![0, 255, 3], vec![255, 0, 254]).unwrap();
+        storage.put(b"users".to_vec(), b"other".to_vec()).unwrap();
+
+        drop(storage);
+        let storage = new_bit_cask(data_dir).unwrap();
+        assert_eq!(vec![255, 0, 254], storage.get(&[0, 255, 3]).unwrap());
+        // lexicographic order puts "user/10" before "user/9"
+        let mut r = storage.range(b"user/", b"user/~").unwrap();
+        r.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(2, r.len());
+        assert_eq!(b"user/10".to_vec(), r[0].key);
+        assert_eq!(b"ten".to_vec(), r[0].value);
+        assert_eq!(b"user/9".to_vec(), r[1].key);
+        assert_eq!(b"nine".to_vec(), r[1].value);
+        assert!(storage.range(b"z", b"a").unwrap().is_empty());
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn delete_survives_restart_test() {
+        let data_dir = "test-data-delete-restart";
+        let _ = fs::remove_dir_all(data_dir);
+        let mut storage = new_bit_cask(data_dir).unwrap();
+        storage.put(b"1".to_vec(), b"first".to_vec()).unwrap();
+        storage.put(b"2".to_vec(), b"second".to_vec()).unwrap();
+        storage.delete(b"1").unwrap();
+        assert!(storage.get(b"1").unwrap().is_empty());
+
+        drop(storage);
+        let storage = new_bit_cask(data_dir).unwrap();
+        assert!(storage.get(b"1").unwrap().is_empty());
+        assert_eq!(b"second".to_vec(), storage.get(b"2").unwrap());
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn delete_survives_compaction_test() {
+        let data_dir = "test-data-delete-compaction";
+        let _ = fs::remove_dir_all(data_dir);
+        let mut storage = new_bit_cask(data_dir).unwrap();
+        storage.put(b"1".to_vec(), b"first".to_vec()).unwrap();
+        storage.put(b"2".to_vec(), b"second".to_vec()).unwrap();
+        storage.merge_all().unwrap();
+
+        // key 1 is in the hint file now, the tombstone is only in the active file
+        storage.delete(b"1").unwrap();
+        drop(storage);
+        let storage = new_bit_cask(data_dir).unwrap();
+        assert!(storage.get(b"1").unwrap().is_empty());
+        assert_eq!(b"second".to_vec(), storage.get(b"2").unwrap());
+
+        // and merging the tombstone away does not bring the value back
+        storage.merge_all().unwrap();
+        drop(storage);
+        let storage = new_bit_cask(data_dir).unwrap();
+        assert!(storage.get(b"1").unwrap().is_empty());
+        assert_eq!(b"second".to_vec(), storage.get(b"2").unwrap());
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn sync_policy_test() {
+        let data_dir = "test-data-sync-policy";
+        let _ = fs::remove_dir_all(data_dir);
+        let options = BitCaskOptions::new().sync_policy(SyncPolicy::EveryWrite);
+        let mut storage = open(data_dir, options).unwrap();
+        storage.put(b"1".to_vec(), b"synced".to_vec()).unwrap();
+        storage
+            .batch_put_with_sync_policy(
+                vec![KV {
+                    key: b"2".to_vec(),
+                    value: b"not synced".to_vec(),
+                }],
+                SyncPolicy::OsManaged,
+            )
+            .unwrap();
+
+        assert_eq!(
+            Ok(SyncPolicy::EveryMillis(10)),
+            "10".parse().map_err(|_| ())
+        );
+        assert!("0".parse::<SyncPolicy>().is_err());
+        let every_0_millis = BitCaskOptions::new().sync_policy(SyncPolicy::EveryMillis(0));
+        let err = open("test-data-sync-policy-0", every_0_millis)
+            .err()
+            .unwrap();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+        assert!(!Path::new("test-data-sync-policy-0").exists());
+        let options = BitCaskOptions::new().sync_policy(SyncPolicy::EveryMillis(10));
+        drop(storage);
+        let mut storage = open(data_dir, options).unwrap();
+        assert_eq!(b"synced".to_vec(), storage.get(b"1").unwrap());
+        assert_eq!(b"not synced".to_vec(), storage.get(b"2").unwrap());
+        storage
+            .put(b"3".to_vec(), b"synced later".to_vec())
+            .unwrap();
+        // a sync that started after the write
+        let (_, syncs) = storage.job_runs();
+        assert!(storage.wait_for_job_runs(0, syncs + 2, Duration::from_secs(10)));
+
+        drop(storage);
+        let storage = new_bit_cask(data_dir).unwrap();
+        assert_eq!(b"synced later".to_vec(), storage.get(b"3").unwrap());
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn writes_during_compaction_test() {
+        let data_dir = "test-data-concurrent-compaction";
+        let _ = fs::remove_dir_all(data_dir);
+        let mut storage = new_bit_cask(data_dir).unwrap();
+        let value = vec![b'x'; 1000];
+        for i in 0..20_000u32 {
+            storage
+                .put(i.to_be_bytes().to_vec(), value.clone())
+                .unwrap();
+        }
+
+        // overwrite and delete keys while a merge copies their old values
+        let merger = storage.clone();
+        let handle = thread::spawn(move || merger.merge_all());
+        thread::sleep(Duration::from_millis(100));
+        let mut expected = HashMap::new();
+        for round in 0..20u32 {
+            for i in (0..20_000u32).step_by(97) {
+                let key = i.to_be_bytes().to_vec();
+                if (i + round) % 3 == 0 {
+                    storage.delete(&key).unwrap();
+                    expected.insert(key, vec![]);
+                } else {
+                    let v = format!("{}-{}", i, round).into_bytes();
+                    storage.put(key.clone(), v.clone()).unwrap();
+                    expected.insert(key, v);
+                }
+            }
+        }
+        handle.join().unwrap().unwrap();
+
+        for (key, v) in &expected {
+            assert_eq!(*v, storage.get(key).unwrap());
+        }
+        assert_eq!(value, storage.get(&1u32.to_be_bytes()).unwrap());
+        drop(storage);
+        let storage = new_bit_cask(data_dir).unwrap();
+        for (key, v) in &expected {
+            assert_eq!(*v, storage.get(key).unwrap());
+        }
+        assert_eq!(value, storage.get(&1u32.to_be_bytes()).unwrap());
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn options_test() {
+        let data_dir = "test-data-options";
+        let _ = fs::remove_dir_all(data_dir);
+        let options = BitCaskOptions::new()
+            .max_file_size(1000)
+            .background_merge(false);
+        let mut storage = open(data_dir, options).unwrap();
+        for i in 0..100u32 {
+            storage
+                .put(i.to_be_bytes().to_vec(), vec![b'x'; 100])
+                .unwrap();
+        }
+        // 132 byte records, rolled over once a file goes past 1000 bytes
+        assert_eq!(13, written_data_files(data_dir).len());
+
+        // a read only store reads the files without adding any
+        let files = fs::read_dir(data_dir).unwrap().count();
+        drop(storage);
+        let mut storage = open(data_dir, BitCaskOptions::new().read_only(true)).unwrap();
+        assert_eq!(vec![b'x'; 100], storage.get(&7u32.to_be_bytes()).unwrap());
+        assert!(storage.put(b"1".to_vec(), b"1".to_vec()).is_err());
+        assert!(storage.merge_all().is_err());
+        assert_eq!(files, fs::read_dir(data_dir).unwrap().count());
+        assert!(open(
+            "test-data-options-missing",
+            BitCaskOptions::new().read_only(true)
+        )
+        .is_err());
+
+        // every file is all garbage once the keys are deleted, so the next background merge
+        // deletes them
+        let thresholds = CompactionThresholds {
+            dead_ratio: 0.9,
+            dead_bytes: u64::MAX,
+        };
+        let options = BitCaskOptions::new()
+            .merge_interval(Duration::from_millis(50))
+            .compaction_thresholds(thresholds);
+        drop(storage);
+        let mut storage = open(data_dir, options).unwrap();
+        for i in 0..100u32 {
+            storage.delete(&i.to_be_bytes()).unwrap();
+        }
+        // a merge that started after the deletes
+        let (merges, _) = storage.job_runs();
+        assert!(storage.wait_for_job_runs(merges + 2, 0, Duration::from_secs(10)));
+        assert_eq!(1, written_data_files(data_dir).len());
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn hint_files_test() {
+        let data_dir = "test-data-hint-files";
+        let _ = fs::remove_dir_all(data_dir);
+        let options = BitCaskOptions::new()
+            .max_file_size(1000)
+            .background_merge(false);
+        let mut storage = open(data_dir, options).unwrap();
+        for i in 0..100u32 {
+            storage
+                .put(i.to_be_bytes().to_vec(), vec![b'x'; 100])
+                .unwrap();
+        }
+        storage.delete(&3u32.to_be_bytes()).unwrap();
+
+        // every data file gets a hint file once the store restarts, the active one included
+        drop(storage);
+        let storage = open(data_dir, options).unwrap();
+        let data_files = written_data_files(data_dir);
+        let hint_file = |f: &String| f.replace("data-file", "hint-file");
+        for data_file in &data_files {
+            assert!(Path::new(&hint_file(data_file)).exists());
+        }
+        assert_eq!(vec![b'x'; 100], storage.get(&7u32.to_be_bytes()).unwrap());
+        assert!(storage.get(&3u32.to_be_bytes()).unwrap().is_empty());
+
+        // a hint torn by a crash is ignored, and written again
+        let last_hint = hint_file(data_files.last().unwrap());
+        let hint_len = fs::metadata(&last_hint).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&last_hint).unwrap();
+        file.set_len(hint_len - 5).unwrap();
+        drop(storage);
+        let storage = open(data_dir, options).unwrap();
+        assert_eq!(hint_len, fs::metadata(&last_hint).unwrap().len());
+        for i in 0..100u32 {
+            let expected = if i == 3 { vec![] } else { vec![b'x'; 100] };
+            assert_eq!(expected, storage.get(&i.to_be_bytes()).unwrap());
+        }
+
+        // compacted files get theirs from the compaction
+        storage.merge_all().unwrap();
+        for data_file in written_data_files(data_dir) {
+            assert!(Path::new(&hint_file(&data_file)).exists());
+        }
+        drop(storage);
+        let storage = open(data_dir, options).unwrap();
+        assert_eq!(vec![b'x'; 100], storage.get(&99u32.to_be_bytes()).unwrap());
+        assert!(storage.get(&3u32.to_be_bytes()).unwrap().is_empty());
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn paged_key_index_test() {
+        let data_dir = "test-data-paged-key-index";
+        let _ = fs::remove_dir_all(data_dir);
+        let options = BitCaskOptions::new()
+            .key_index(KeyIndex::Paged {
+                cache_bytes: 64 * 1024,
+            })
+            .background_merge(false);
+        let mut storage = open(data_dir, options).unwrap();
+        // enough keys for the index to write several runs to disk
+        let mut expected = HashMap::new();
+        for i in 0..120_000u32 {
+            let value = (i % 1000).to_be_bytes().to_vec();
+            storage
+                .put(i.to_be_bytes().to_vec(), value.clone())
+                .unwrap();
+            expected.insert(i, value);
+        }
+        for i in (0..120_000u32).step_by(7) {
+            storage.delete(&i.to_be_bytes()).unwrap();
+            expected.remove(&i);
+        }
+        for i in (0..120_000u32).step_by(5) {
+            let value = b"new".to_vec();
+            storage
+                .put(i.to_be_bytes().to_vec(), value.clone())
+                .unwrap();
+            expected.insert(i, value);
+        }
+
+        let check = |storage: &mut dyn KVStorage| {
+            for i in (0..120_000u32).step_by(13) {
+                match expected.get(&i) {
+                    Some(value) => assert_eq!(*value, storage.get(&i.to_be_bytes()).unwrap()),
+                    None => assert!(storage.get(&i.to_be_bytes()).unwrap().is_empty()),
+                }
+            }
+            let range = storage
+                .range(&1000u32.to_be_bytes(), &2000u32.to_be_bytes())
+                .unwrap();
+            let mut keys: Vec<u32> = expected
+                .keys()
+                .filter(|k| (1000..=2000).contains(*k))
+                .cloned()
+                .collect();
+            keys.sort();
+            let mut found: Vec<u32> = range
+                .iter()
+                .map(|kv| u32::from_be_bytes(kv.key.clone().try_into().unwrap()))
+                .collect();
+            found.sort();
+            assert_eq!(keys, found);
+        };
+        check(&mut storage);
+        storage.merge_all().unwrap();
+        check(&mut storage);
+
+        drop(storage);
+        let mut storage = open(data_dir, options).unwrap();
+        check(&mut storage);
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn dir_lock_test() {
+        let data_dir = "test-data-dir-lock";
+        let _ = fs::remove_dir_all(data_dir);
+        let mut storage = new_bit_cask(data_dir).unwrap();
+        storage.put(b"1".to_vec(), b"1".to_vec()).unwrap();
+        let err = new_bit_cask(data_dir).err().unwrap();
+        assert_eq!(ErrorKind::ResourceBusy, err.kind());
+        let read_only = BitCaskOptions::new().read_only(true);
+        assert!(open(data_dir, read_only).is_err());
+
+        // released once every handle is dropped, even with the background merge still around
+        let clone = storage.clone();
+        drop(storage);
+        assert!(new_bit_cask(data_dir).is_err());
+        drop(clone);
+        let storage = open(data_dir, read_only).unwrap();
+        let other = open(data_dir, read_only).unwrap();
+        assert!(new_bit_cask(data_dir).is_err());
+        drop(storage);
+        drop(other);
+        let storage = new_bit_cask(data_dir).unwrap();
+        assert_eq!(b"1".to_vec(), storage.get(b"1").unwrap());
+
+        // a read only store locks a dir that has no lock file yet
+        drop(storage);
+        fs::remove_file(format!("{}/LOCK", data_dir)).unwrap();
+        let storage = open(data_dir, read_only).unwrap();
+        assert_eq!(b"1".to_vec(), storage.get(b"1").unwrap());
+        let err = new_bit_cask(data_dir).err().unwrap();
+        assert_eq!(ErrorKind::ResourceBusy, err.kind());
+        drop(storage);
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn close_test() {
+        let data_dir = "test-data-close";
+        let _ = fs::remove_dir_all(data_dir);
+        // jobs that would not run again for an hour
+        let options = BitCaskOptions::new()
+            .merge_interval(Duration::from_secs(3600))
+            .sync_policy(SyncPolicy::EveryMillis(3_600_000));
+        for i in 0..20u32 {
+            let mut storage = open(data_dir, options).unwrap();
+            storage
+                .put(i.to_be_bytes().to_vec(), b"v".to_vec())
+                .unwrap();
+            let key_dir = storage.key_dir_weak();
+            let start = Instant::now();
+            if i % 2 == 0 {
+                storage.close().unwrap();
+            } else {
+                drop(storage);
+            }
+            assert!(start.elapsed() < Duration::from_secs(1));
+            // the jobs are done with their handles of the store
+            assert!(key_dir.upgrade().is_none());
+        }
+
+        // a merge running when the store is closed finishes first
+        let options = BitCaskOptions::new().merge_interval(Duration::from_millis(10));
+        let mut storage = open(data_dir, options).unwrap();
+        for i in 0..20u32 {
+            storage.delete(&i.to_be_bytes()).unwrap();
+            storage
+                .put(i.to_be_bytes().to_vec(), b"w".to_vec())
+                .unwrap();
+        }
+        let (merges, _) = storage.job_runs();
+        assert!(storage.wait_for_job_runs(merges + 1, 0, Duration::from_secs(10)));
+        storage.close().unwrap();
+        let storage = open(data_dir, options).unwrap();
+        for i in 0..20u32 {
+            assert_eq!(b"w".to_vec(), storage.get(&i.to_be_bytes()).unwrap());
+        }
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn manifest_test() {
+        let data_dir = "test-data-manifest";
+        let _ = fs::remove_dir_all(data_dir);
+        let options = BitCaskOptions::new()
+            .max_file_size(1000)
+            .background_merge(false);
+        let mut storage = open(data_dir, options).unwrap();
+        for i in 0..30u32 {
+            storage
+                .put(i.to_be_bytes().to_vec(), vec![b'x'; 100])
+                .unwrap();
+        }
+        // files are numbered in the order they are created
+        let expected: Vec<String> = (0..4)
+            .map(|n| format!("{}/data-file{:010}", data_dir, n))
+            .collect();
+        assert_eq!(expected, written_data_files(data_dir));
+
+        // a compaction that stopped before installing its output, a data file the manifest
+        // does not know and a half-written hint
+        let mut manifest = Manifest::open(data_dir, false).unwrap();
+        let merge_output = manifest.new_merge_output().unwrap();
+        fs::write(&merge_output, b"half-written").unwrap();
+        let stray = format!("{}/data-file9999999999", data_dir);
+        fs::write(&stray, b"stray").unwrap();
+        let temp_hint = format!("{}/hint-file0000000001.tmp", data_dir);
+        fs::write(&temp_hint, b"temp").unwrap();
+
+        drop(storage);
+        let storage = open(data_dir, options).unwrap();
+        for path in [merge_output, stray, temp_hint] {
+            assert!(!Path::new(&path).exists());
+        }
+        assert_eq!(expected, written_data_files(data_dir));
+        for i in 0..30u32 {
+            assert_eq!(vec![b'x'; 100], storage.get(&i.to_be_bytes()).unwrap());
+        }
+
+        // the output of a compaction is installed before the merged files are deleted
+        storage.merge_all().unwrap();
+        drop(storage);
+        let storage = open(data_dir, options).unwrap();
+        for i in 0..30u32 {
+            assert_eq!(vec![b'x'; 100], storage.get(&i.to_be_bytes()).unwrap());
+        }
+
+        // a data dir from before the manifest is taken as it is
+        fs::remove_file(format!("{}/MANIFEST", data_dir)).unwrap();
+        drop(storage);
+        let storage = open(data_dir, options).unwrap();
+        for i in 0..30u32 {
+            assert_eq!(vec![b'x'; 100], storage.get(&i.to_be_bytes()).unwrap());
+        }
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    // data files with records in them, oldest first
+    fn written_data_files(data_dir: &str) -> Vec<String> {
+        let mut files: Vec<String> = fs::read_dir(data_dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.metadata().unwrap().len() > 0)
+            .map(|p| p.to_str().unwrap().to_string())
+            .filter(|p| p.contains("data-file"))
+            .collect();
+        files.sort();
+        files
+    }
+
+    // the only data file with records in it
+    fn written_data_file(data_dir: &str) -> String {
+        let mut files = written_data_files(data_dir);
+        assert_eq!(1, files.len());
+        files.pop().unwrap()
+    }
+
+    #[test]
+    fn selective_compaction_test() {
+        let data_dir = "test-data-selective-compaction";
+        let _ = fs::remove_dir_all(data_dir);
+        let mut storage = new_bit_cask(data_dir).unwrap();
+        for i in 0..100u32 {
+            storage
+                .put(i.to_be_bytes().to_vec(), b"first".to_vec())
+                .unwrap();
+        }
+        let kept = written_data_file(data_dir);
+
+        // a file where two thirds of the records are overwritten, with a tombstone for a key
+        // whose value is in the other file
+        drop(storage);
+        let mut storage = new_bit_cask(data_dir).unwrap();
+        for round in 0..3 {
+            for i in 200..300u32 {
+                let v = format!("round-{}", round).into_bytes();
+                storage.put(i.to_be_bytes().to_vec(), v).unwrap();
+            }
+        }
+        storage.delete(&50u32.to_be_bytes()).unwrap();
+        let garbage = written_data_files(data_dir).pop().unwrap();
+
+        // header, 4 byte key and value
+        let first_size = 36 + 4 + 5;
+        let round_size = 36 + 4 + 7;
+        let kept_stats = storage.file_stats(&kept);
+        assert_eq!(99 * first_size, kept_stats.live_bytes);
+        assert_eq!(first_size, kept_stats.dead_bytes);
+        let garbage_stats = storage.file_stats(&garbage);
+        assert_eq!(100 * round_size + 36 + 4, garbage_stats.live_bytes);
+        assert_eq!(200 * round_size, garbage_stats.dead_bytes);
+
+        // rebuilt from the files on restart
+        drop(storage);
+        let storage = new_bit_cask(data_dir).unwrap();
+        assert_eq!(kept_stats, storage.file_stats(&kept));
+        assert_eq!(garbage_stats, storage.file_stats(&garbage));
+
+        storage.merge().unwrap();
+        assert!(Path::new(&kept).exists());
+        assert!(!Path::new(&garbage).exists());
+        drop(storage);
+        let storage = new_bit_cask(data_dir).unwrap();
+        assert_eq!(
+            b"first".to_vec(),
+            storage.get(&10u32.to_be_bytes()).unwrap()
+        );
+        assert_eq!(
+            b"round-2".to_vec(),
+            storage.get(&250u32.to_be_bytes()).unwrap()
+        );
+        assert!(storage.get(&50u32.to_be_bytes()).unwrap().is_empty());
+
+        storage.merge_all().unwrap();
+        assert!(!Path::new(&kept).exists());
+        drop(storage);
+        let storage = new_bit_cask(data_dir).unwrap();
+        assert_eq!(
+            b"first".to_vec(),
+            storage.get(&10u32.to_be_bytes()).unwrap()
+        );
+        assert!(storage.get(&50u32.to_be_bytes()).unwrap().is_empty());
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn overwrite_order_test() {
+        let data_dir = "test-data-overwrite-order";
+        let _ = fs::remove_dir_all(data_dir);
+        let thresholds = CompactionThresholds {
+            dead_ratio: 0.5,
+            dead_bytes: u64::MAX,
+        };
+        let options = BitCaskOptions::new()
+            .max_file_size(1000)
+            .compaction_thresholds(thresholds)
+            .background_merge(false);
+        let mut storage = open(data_dir, options).unwrap();
+        // each large value rolls the active file over. The first file stays mostly live, and
+        // the second one is mostly garbage once "padding-2" is overwritten
+        storage.put(b"key".to_vec(), b"old".to_vec()).unwrap();
+        storage.put(b"padding-1".to_vec(), vec![0; 1000]).unwrap();
+        storage.put(b"key".to_vec(), b"new".to_vec()).unwrap();
+        storage.put(b"padding-2".to_vec(), vec![0; 1000]).unwrap();
+        storage.put(b"padding-2".to_vec(), vec![0; 10]).unwrap();
+
+        // the compaction copies the newest value of the key to a new file, written right
+        // after the old value, within the same second
+        storage.merge().unwrap();
+        assert_eq!(b"new".to_vec(), storage.get(b"key").unwrap());
+        drop(storage);
+        let storage = open(data_dir, options).unwrap();
+        assert_eq!(b"new".to_vec(), storage.get(b"key").unwrap());
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn torn_tail_recovery_test() {
+        let data_dir = "test-data-torn-tail";
+        let _ = fs::remove_dir_all(data_dir);
+        let mut storage = new_bit_cask(data_dir).unwrap();
+        storage.put(b"1".to_vec(), b"first".to_vec()).unwrap();
+        storage.put(b"2".to_vec(), b"second".to_vec()).unwrap();
+
+        // half of a record header, as left by a crash in the middle of a write
+        let filename = written_data_file(data_dir);
+        let valid_len = fs::metadata(&filename).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&filename).unwrap();
+        file.write_all(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]).unwrap();
+
+        drop(storage);
+        let storage = new_bit_cask(data_dir).unwrap();
+        assert_eq!(b"first".to_vec(), storage.get(b"1").unwrap());
+        assert_eq!(b"second".to_vec(), storage.get(b"2").unwrap());
+        assert_eq!(valid_len, fs::metadata(&filename).unwrap().len());
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_record_test() {
+        let data_dir = "test-data-corrupt-record";
+        let _ = fs::remove_dir_all(data_dir);
+        let mut storage = new_bit_cask(data_dir).unwrap();
+        storage.put(b"1".to_vec(), b"first".to_vec()).unwrap();
+        storage.put(b"2".to_vec(), b"second".to_vec()).unwrap();
+
+        // flip the first byte of the value of key 1, after its 36 byte header and 1 byte key
+        let filename = written_data_file(data_dir);
+        let mut file = OpenOptions::new().write(true).open(&filename).unwrap();
+        file.seek(SeekFrom::Start(37)).unwrap();
+        file.write_all(b"F").unwrap();
+
+        assert!(storage.get(b"1").is_err());
+        drop(storage);
+        let storage = new_bit_cask(data_dir).unwrap();
+        assert!(storage.get(b"1").unwrap().is_empty());
+        assert_eq!(b"second".to_vec(), storage.get(b"2").unwrap());
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_length_test() {
+        let data_dir = "test-data-corrupt-length";
+        // the value length of key 1 made to run past the end of the file, and made shorter
+        for (position, byte) in [(21, 0xFF), (27, 2)] {
+            let _ = fs::remove_dir_all(data_dir);
+            let mut storage = new_bit_cask(data_dir).unwrap();
+            storage.put(b"1".to_vec(), b"first".to_vec()).unwrap();
+            storage.put(b"2".to_vec(), b"second".to_vec()).unwrap();
+            storage.put(b"3".to_vec(), b"third".to_vec()).unwrap();
+            drop(storage);
+
+            let filename = written_data_file(data_dir);
+            let len = fs::metadata(&filename).unwrap().len();
+            let mut file = OpenOptions::new().write(true).open(&filename).unwrap();
+            file.seek(SeekFrom::Start(position)).unwrap();
+            file.write_all(&[byte]).unwrap();
+
+            // the records after it are found again, and the file is not truncated
+            for _ in 0..2 {
+                let storage = new_bit_cask(data_dir).unwrap();
+                assert!(storage.get(b"1").unwrap().is_empty());
+                assert_eq!(b"second".to_vec(), storage.get(b"2").unwrap());
+                assert_eq!(b"third".to_vec(), storage.get(b"3").unwrap());
+                assert_eq!(len, fs::metadata(&filename).unwrap().len());
+            }
+        }
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    // Files of the data dir that the process has open, and whether each was deleted
+    #[cfg(target_os = "linux")]
+    fn open_files(data_dir: &str) -> Vec<(String, bool)> {
+        let dir = fs::canonicalize(data_dir).unwrap();
+        fs::read_dir("/proc/self/fd")
+            .unwrap()
+            .filter_map(|fd| fs::read_link(fd.ok()?.path()).ok())
+            .filter(|target| target.starts_with(&dir))
+            .map(|target| {
+                let target = target.to_string_lossy().to_string();
+                let deleted = target.ends_with(" (deleted)");
+                (target, deleted)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn read_handles_test() {
+        let data_dir = "test-data-read-handles";
+        let _ = fs::remove_dir_all(data_dir);
+        let options = BitCaskOptions::new()
+            .max_file_size(1000)
+            .max_open_files(2)
+            .background_merge(false);
+        let mut storage = open(data_dir, options).unwrap();
+        for i in 0..100u32 {
+            storage
+                .put(i.to_be_bytes().to_vec(), vec![i as u8; 100])
+                .unwrap();
+        }
+        assert!(written_data_files(data_dir).len() > 10);
+
+        // reads that go back and forth between more files than are kept open
+        for _ in 0..3 {
+            for i in (0..100u32).step_by(7) {
+                assert_eq!(vec![i as u8; 100], storage.get(&i.to_be_bytes()).unwrap());
+            }
+        }
+        let all = storage
+            .range(&0u32.to_be_bytes(), &99u32.to_be_bytes())
+            .unwrap();
+        assert_eq!(100, all.len());
+        #[cfg(target_os = "linux")]
+        assert!(open_files(data_dir).len() <= 3);
+
+        // the merges delete files that were open for reads
+        for i in 0..50u32 {
+            storage
+                .put(i.to_be_bytes().to_vec(), vec![i as u8 + 1; 100])
+                .unwrap();
+        }
+        storage.merge().unwrap();
+        storage.merge_all().unwrap();
+        #[cfg(target_os = "linux")]
+        assert!(open_files(data_dir).iter().all(|(_, deleted)| !deleted));
+        let expected = |i: u32| vec![if i < 50 { i as u8 + 1 } else { i as u8 }; 100];
+        for i in 0..100u32 {
+            assert_eq!(expected(i), storage.get(&i.to_be_bytes()).unwrap());
+        }
+
+        drop(storage);
+        let storage = open(data_dir, options).unwrap();
+        for i in 0..100u32 {
+            assert_eq!(expected(i), storage.get(&i.to_be_bytes()).unwrap());
+        }
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    // Files of the data dir that are mapped in memory
+    #[cfg(target_os = "linux")]
+    fn mapped_files(data_dir: &str) -> HashSet<String> {
+        let dir = fs::canonicalize(data_dir).unwrap();
+        fs::read_to_string("/proc/self/maps")
+            .unwrap()
+            .lines()
+            .filter_map(|line| line.split_once('/').map(|(_, path)| format!("/{}", path)))
+            .filter(|path| path.starts_with(&*dir.to_string_lossy()))
+            .collect()
+    }
+
+    #[test]
+    fn mmap_reads_test() {
+        let data_dir = "test-data-mmap-reads";
+        let _ = fs::remove_dir_all(data_dir);
+        let options = BitCaskOptions::new()
+            .max_file_size(1000)
+            .max_open_files(4)
+            .mmap_reads(true)
+            .background_merge(false);
+        let mut storage = open(data_dir, options).unwrap();
+        for i in 0..60u32 {
+            storage
+                .put(i.to_be_bytes().to_vec(), vec![i as u8; 100])
+                .unwrap();
+            // the active file is read while it grows, and again once it is sealed
+            for j in (0..=i).rev().take(12) {
+                assert_eq!(vec![j as u8; 100], storage.get(&j.to_be_bytes()).unwrap());
+            }
+        }
+        let all = storage
+            .range(&0u32.to_be_bytes(), &59u32.to_be_bytes())
+            .unwrap();
+        assert_eq!(60, all.len());
+        assert!(all.iter().all(|kv| kv.value == vec![kv.key[3]; 100]));
+        #[cfg(target_os = "linux")]
+        {
+            let mapped = mapped_files(data_dir);
+            assert!(!mapped.is_empty() && mapped.len() <= 4);
+            let active = written_data_files(data_dir).pop().unwrap();
+            let active = fs::canonicalize(active).unwrap();
+            assert!(!mapped.contains(&*active.to_string_lossy()));
+        }
+
+        storage.merge_all().unwrap();
+        #[cfg(target_os = "linux")]
+        assert!(mapped_files(data_dir)
+            .iter()
+            .all(|f| !f.ends_with(" (deleted)")));
+        for i in 0..60u32 {
+            assert_eq!(vec![i as u8; 100], storage.get(&i.to_be_bytes()).unwrap());
+        }
+
+        drop(storage);
+        let storage = open(data_dir, options.read_only(true)).unwrap();
+        for i in 0..60u32 {
+            assert_eq!(vec![i as u8; 100], storage.get(&i.to_be_bytes()).unwrap());
+        }
+        drop(storage);
+        #[cfg(target_os = "linux")]
+        assert!(mapped_files(data_dir).is_empty());
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn value_cache_test() {
+        let data_dir = "test-data-value-cache";
+        let _ = fs::remove_dir_all(data_dir);
+        let options = BitCaskOptions::new()
+            .max_file_size(1000)
+            .value_cache_bytes(1000)
+            .background_merge(false);
+        let mut storage = open(data_dir, options).unwrap();
+        for i in 0..20u32 {
+            storage
+                .put(i.to_be_bytes().to_vec(), vec![i as u8; 100])
+                .unwrap();
+        }
+        for _ in 0..10 {
+            assert_eq!(vec![1; 100], storage.get(&1u32.to_be_bytes()).unwrap());
+        }
+        let stats = storage.cache_stats();
+        assert_eq!((9, 1, 1), (stats.hits, stats.misses, stats.entries));
+
+        // writes and deletes are seen through the cache
+        storage
+            .put(1u32.to_be_bytes().to_vec(), b"new".to_vec())
+            .unwrap();
+        assert_eq!(b"new".to_vec(), storage.get(&1u32.to_be_bytes()).unwrap());
+        assert_eq!(b"new".to_vec(), storage.get(&1u32.to_be_bytes()).unwrap());
+        storage.delete(&1u32.to_be_bytes()).unwrap();
+        assert!(storage.get(&1u32.to_be_bytes()).unwrap().is_empty());
+        assert_eq!(0, storage.cache_stats().entries);
+
+        // more values than fit
+        for i in 2..20u32 {
+            assert_eq!(vec![i as u8; 100], storage.get(&i.to_be_bytes()).unwrap());
+        }
+        let stats = storage.cache_stats();
+        assert!(stats.evictions > 0 && stats.bytes <= 1000);
+
+        // relocated keys keep their cached values, which still match the disk
+        storage.merge_all().unwrap();
+        let hits = storage.cache_stats().hits;
+        for i in (2..20u32).rev() {
+            assert_eq!(vec![i as u8; 100], storage.get(&i.to_be_bytes()).unwrap());
+        }
+        assert!(storage.cache_stats().hits > hits);
+        assert!(storage.get(&1u32.to_be_bytes()).unwrap().is_empty());
+
+        drop(storage);
+        let storage = open(data_dir, BitCaskOptions::new()).unwrap();
+        assert_eq!(vec![2; 100], storage.get(&2u32.to_be_bytes()).unwrap());
+        assert_eq!(0, storage.cache_stats().misses);
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn ttl_test() {
+        let data_dir = "test-data-ttl";
+        let _ = fs::remove_dir_all(data_dir);
+        let options = BitCaskOptions::new()
+            .value_cache_bytes(10_000)
+            .background_merge(false);
+        let mut storage = open(data_dir, options).unwrap();
+        storage.put(b"kept".to_vec(), b"value".to_vec()).unwrap();
+        storage
+            .put_with_ttl(
+                b"session".to_vec(),
+                b"s".to_vec(),
+                Duration::from_secs(3600),
+            )
+            .unwrap();
+        let expired: Vec<KV> = (0..10u8)
+            .map(|i| KV {
+                key: vec![b'e', i],
+                value: vec![i; 1000],
+            })
+            .collect();
+        let past = SystemTime::now() - Duration::from_secs(1);
+        storage.batch_put_expiring_at(expired, past).unwrap();
+        storage
+            .put_with_ttl(b"short".to_vec(), b"s".to_vec(), Duration::from_millis(50))
+            .unwrap();
+        assert_eq!(b"s".to_vec(), storage.get(b"short").unwrap());
+
+        thread::sleep(Duration::from_millis(60));
+        // expired keys are invisible, also through the value cache
+        assert!(storage.get(b"short").unwrap().is_empty());
+        assert!(storage.get(&[b'e', 1]).unwrap().is_empty());
+        assert_eq!(b"s".to_vec(), storage.get(b"session").unwrap());
+        let keys: Vec<Vec<u8>> = storage
+            .range(b"a", b"z")
+            .unwrap()
+            .into_iter()
+            .map(|kv| kv.key)
+            .collect();
+        assert_eq!(vec![b"kept".to_vec(), b"session".to_vec()], keys);
+
+        // expiries survive a restart, and compaction drops the expired keys
+        drop(storage);
+        let storage = open(data_dir, options).unwrap();
+        assert!(storage.get(&[b'e', 1]).unwrap().is_empty());
+        assert_eq!(b"s".to_vec(), storage.get(b"session").unwrap());
+        storage.merge_all().unwrap();
+        let size: u64 = written_data_files(data_dir)
+            .iter()
+            .map(|f| fs::metadata(f).unwrap().len())
+            .sum();
+        assert!(size < 1000);
+        assert_eq!(b"value".to_vec(), storage.get(b"kept").unwrap());
+        assert_eq!(b"s".to_vec(), storage.get(b"session").unwrap());
+        drop(storage);
+        let storage = open(data_dir, options).unwrap();
+        assert!(storage.get(b"short").unwrap().is_empty());
+        assert_eq!(2, storage.range(b"a", b"z").unwrap().len());
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn snapshot_test() {
+        let data_dir = "test-data-snapshot";
+        let _ = fs::remove_dir_all(data_dir);
+        let options = BitCaskOptions::new()
+            .max_file_size(1000)
+            .background_merge(false);
+        let mut storage = open(data_dir, options).unwrap();
+        for i in 0..1000u32 {
+            storage
+                .put(i.to_be_bytes().to_vec(), format!("v1-{}", i).into_bytes())
+                .unwrap();
+        }
+        let snapshot = storage.snapshot();
+        let mut iter = snapshot.iter(&RangeQuery::new(Unbounded, Unbounded));
+        let mut seen: Vec<KV> = iter.by_ref().take(300).map(|kv| kv.unwrap()).collect();
+
+        // overwritten, deleted and new keys, and every file the snapshot reads merged away
+        for i in 0..1000u32 {
+            storage
+                .put(i.to_be_bytes().to_vec(), format!("v2-{}", i).into_bytes())
+                .unwrap();
+        }
+        for i in 500..600u32 {
+            storage.delete(&i.to_be_bytes()).unwrap();
+        }
+        storage
+            .put(5000u32.to_be_bytes().to_vec(), b"new".to_vec())
+            .unwrap();
+        storage.merge_all().unwrap();
+        let files_while_pinned = written_data_files(data_dir).len();
+
+        seen.extend(iter.map(|kv| kv.unwrap()));
+        assert_eq!(1000, seen.len());
+        for (i, kv) in seen.iter().enumerate() {
+            assert_eq!((i as u32).to_be_bytes().to_vec(), kv.key);
+            assert_eq!(format!("v1-{}", i).into_bytes(), kv.value);
+        }
+        assert_eq!(
+            b"v1-550".to_vec(),
+            snapshot.get(&550u32.to_be_bytes()).unwrap()
+        );
+        assert!(snapshot.get(&5000u32.to_be_bytes()).unwrap().is_empty());
+        let range = snapshot
+            .range(&0u32.to_be_bytes(), &u32::MAX.to_be_bytes())
+            .unwrap();
+        assert_eq!(1000, range.len());
+        assert!(range.iter().all(|kv| kv.value.starts_with(b"v1-")));
+        assert_eq!(b"v2-1".to_vec(), storage.get(&1u32.to_be_bytes()).unwrap());
+        assert!(storage.get(&550u32.to_be_bytes()).unwrap().is_empty());
+
+        // the merged files go with the snapshot
+        drop(snapshot);
+        assert!(written_data_files(data_dir).len() < files_while_pinned);
+        #[cfg(target_os = "linux")]
+        assert!(open_files(data_dir).iter().all(|(_, deleted)| !deleted));
+        let range = storage
+            .range(&0u32.to_be_bytes(), &u32::MAX.to_be_bytes())
+            .unwrap();
+        assert_eq!(901, range.len());
+        drop(storage);
+        let storage = open(data_dir, options).unwrap();
+        assert_eq!(b"v2-1".to_vec(), storage.get(&1u32.to_be_bytes()).unwrap());
+        assert!(storage.get(&550u32.to_be_bytes()).unwrap().is_empty());
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn write_batch_test() {
+        let data_dir = "test-data-write-batch";
+        let _ = fs::remove_dir_all(data_dir);
+        let options = BitCaskOptions::new()
+            .max_file_size(1000)
+            .background_merge(false);
+        let mut storage = open(data_dir, options).unwrap();
+        storage.put(b"a".to_vec(), b"old".to_vec()).unwrap();
+        storage.put(b"b".to_vec(), b"old".to_vec()).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.delete(b"a".to_vec());
+        batch.put(b"b".to_vec(), b"new".to_vec());
+        for i in 0..20 {
+            batch.put(format!("k{:02}", i).into_bytes(), vec![b'x'; 100]);
+        }
+        storage.write_batch(batch).unwrap();
+        assert!(storage.get(b"a").unwrap().is_empty());
+        assert_eq!(b"new".to_vec(), storage.get(b"b").unwrap());
+        assert_eq!(vec![b'x'; 100], storage.get(b"k19").unwrap());
+
+        // larger than a file, the batch starts a file of its own instead of spanning two:
+        // the begin and commit markers, the tombstone of a, b and the 20 puts
+        let batch_file = written_data_files(data_dir).pop().unwrap();
+        let batch_size = 2 * 36 + (36 + 1) + (36 + 1 + 3) + 20 * (36 + 3 + 100);
+        assert_eq!(batch_size, fs::metadata(&batch_file).unwrap().len());
+        let stats = storage.file_stats(&batch_file);
+        assert_eq!(batch_size, stats.live_bytes + stats.dead_bytes);
+
+        // a crash before the commit marker is on disk undoes the whole batch
+        drop(storage);
+        let file = OpenOptions::new().write(true).open(&batch_file).unwrap();
+        file.set_len(batch_size - 36).unwrap();
+        let mut storage = open(data_dir, options).unwrap();
+        assert_eq!(b"old".to_vec(), storage.get(b"a").unwrap());
+        assert_eq!(b"old".to_vec(), storage.get(b"b").unwrap());
+        assert!(storage.get(b"k00").unwrap().is_empty());
+
+        // a batch torn in the active file is truncated on the next start
+        storage.put(b"c".to_vec(), b"kept".to_vec()).unwrap();
+        let active_file = written_data_files(data_dir).pop().unwrap();
+        let valid_len = fs::metadata(&active_file).unwrap().len();
+        let mut batch = WriteBatch::new();
+        batch.put(b"x".to_vec(), b"lost".to_vec());
+        batch.delete(b"c".to_vec());
+        storage.write_batch(batch).unwrap();
+        drop(storage);
+        let file_len = fs::metadata(&active_file).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&active_file).unwrap();
+        file.set_len(file_len - 10).unwrap();
+        let storage = open(data_dir, options).unwrap();
+        assert_eq!(b"kept".to_vec(), storage.get(b"c").unwrap());
+        assert!(storage.get(b"x").unwrap().is_empty());
+        assert_eq!(valid_len, fs::metadata(&active_file).unwrap().len());
+        drop(storage);
+
+        // a batch over the maximum size is rejected whole, a bulk load writes it record by
+        // record, rolling over files as it goes
+        let mut storage = open(data_dir, options.max_batch_size(batch_size)).unwrap();
+        let kvs: Vec<KV> = (0..21)
+            .map(|i| KV {
+                key: format!("l{:02}", i).into_bytes(),
+                value: vec![b'x'; 100],
+            })
+            .collect();
+        let files = written_data_files(data_dir).len();
+        let err = storage.batch_put(kvs.clone()).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+        assert!(storage.get(b"l00").unwrap().is_empty());
+        assert_eq!(files, written_data_files(data_dir).len());
+        storage.bulk_load(kvs).unwrap();
+        assert_eq!(vec![b'x'; 100], storage.get(b"l20").unwrap());
+        assert!(written_data_files(data_dir).len() > files + 1);
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn conditional_write_test() {
+        let data_dir = "test-data-conditional-write";
+        let _ = fs::remove_dir_all(data_dir);
+        let options = BitCaskOptions::new().background_merge(false);
+        let mut storage = open(data_dir, options).unwrap();
+        assert!(storage
+            .put_if(b"k".to_vec(), b"v1".to_vec(), &Condition::Absent)
+            .unwrap());
+        assert!(!storage
+            .put_if(b"k".to_vec(), b"other".to_vec(), &Condition::Absent)
+            .unwrap());
+        let (value, v1) = storage.get_versioned(b"k").unwrap().unwrap();
+        assert_eq!(b"v1".to_vec(), value);
+
+        // a stale version or value does not match once the key changed
+        assert!(storage
+            .put_if(b"k".to_vec(), b"v2".to_vec(), &Condition::Version(v1))
+            .unwrap());
+        assert!(!storage
+            .put_if(b"k".to_vec(), b"v3".to_vec(), &Condition::Version(v1))
+            .unwrap());
+        assert!(!storage
+            .delete_if(b"k", &Condition::Value(b"v1".to_vec()))
+            .unwrap());
+        let (value, v2) = storage.get_versioned(b"k").unwrap().unwrap();
+        assert_eq!(b"v2".to_vec(), value);
+        assert!(v2 > v1);
+        assert!(!storage
+            .put_if(b"missing".to_vec(), b"v".to_vec(), &Condition::Version(v2))
+            .unwrap());
+        assert!(storage
+            .delete_if(b"k", &Condition::Value(b"v2".to_vec()))
+            .unwrap());
+        assert_eq!(None, storage.get_versioned(b"k").unwrap());
+
+        // versions are not reused after the records of the key are compacted away
+        storage.merge_all().unwrap();
+        drop(storage);
+        let mut storage = open(data_dir, options).unwrap();
+        assert!(storage
+            .put_if(b"k".to_vec(), b"v4".to_vec(), &Condition::Absent)
+            .unwrap());
+        let (_, v4) = storage.get_versioned(b"k").unwrap().unwrap();
+        assert!(v4 > v2);
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn range_query_test() {
+        let data_dir = "test-data-range-query";
+        let key_indexes = [
+            KeyIndex::Memory,
+            KeyIndex::Paged {
+                cache_bytes: 16 * 4096,
+            },
+        ];
+        for key_index in key_indexes {
+            let _ = fs::remove_dir_all(data_dir);
+            // small files, so that the keys are read from many files at once
+            let options = BitCaskOptions::new()
+                .max_file_size(500)
+                .background_merge(false)
+                .key_index(key_index);
+            let mut storage = open(data_dir, options).unwrap();
+            for i in (0..200u32).rev() {
+                storage
+                    .put(i.to_be_bytes().to_vec(), i.to_string().into_bytes())
+                    .unwrap();
+            }
+            // expired keys are skipped without shortening a page
+            let expired = (0..200u32)
+                .step_by(3)
+                .map(|i| KV {
+                    key: i.to_be_bytes().to_vec(),
+                    value: b"expired".to_vec(),
+                })
+                .collect();
+            storage
+                .batch_put_expiring_at(expired, UNIX_EPOCH + Duration::from_secs(1))
+                .unwrap();
+            let live: Vec<u32> = (0..200u32).filter(|i| i % 3 != 0).collect();
+            let keys = |kvs: &[KV]| -> Vec<u32> {
+                kvs.iter()
+                    .map(|kv| u32::from_be_bytes(kv.key.clone().try_into().unwrap()))
+                    .collect()
+            };
+
+            let all = storage
+                .range(&0u32.to_be_bytes(), &u32::MAX.to_be_bytes())
+                .unwrap();
+            assert_eq!(live, keys(&all));
+            assert!(all
+                .iter()
+                .all(|kv| kv.value == keys(std::slice::from_ref(kv))[0].to_string().into_bytes()));
+
+            for reverse in [false, true] {
+                let query = RangeQuery::new(Unbounded, Unbounded)
+                    .limit(7)
+                    .reverse(reverse);
+                let mut paged = Vec::new();
+                let mut page = storage.scan(&query).unwrap();
+                while let Some(cursor) = page.cursor {
+                    assert_eq!(7, page.kvs.len());
+                    paged.extend(keys(&page.kvs));
+                    page = storage.scan(&query.clone().after(cursor)).unwrap();
+                }
+                paged.extend(keys(&page.kvs));
+                let mut expected = live.clone();
+                if reverse {
+                    expected.reverse();
+                }
+                assert_eq!(expected, paged);
+            }
+
+            let between = |start, end, reverse| {
+                let query = RangeQuery::new(start, end).reverse(reverse);
+                keys(&storage.scan(&query).unwrap().kvs)
+            };
+            let key = |i: u32| i.to_be_bytes().to_vec();
+            assert_eq!(
+                vec![10, 11, 13],
+                between(Included(key(10)), Excluded(key(14)), false)
+            );
+            assert_eq!(
+                vec![14, 13, 11],
+                between(Excluded(key(10)), Included(key(14)), true)
+            );
+            assert_eq!(
+                vec![199, 197, 196],
+                between(Excluded(key(195)), Unbounded, true)
+            );
+            assert_eq!(vec![1, 2], between(Unbounded, Included(key(2)), false));
+            assert!(between(Excluded(key(10)), Excluded(key(10)), false).is_empty());
+            assert!(between(Included(key(20)), Included(key(10)), true).is_empty());
+            drop(storage);
+        }
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn range_iter_test() {
+        let data_dir = "test-data-range-iter";
+        let _ = fs::remove_dir_all(data_dir);
+        let options = BitCaskOptions::new()
+            .max_file_size(1 << 20)
+            .background_merge(false);
+        let mut storage = open(data_dir, options).unwrap();
+        // more values than an iterator reads at once, by count and by bytes
+        for i in 0..2000u32 {
+            storage
+                .put(i.to_be_bytes().to_vec(), vec![i as u8; 10_000])
+                .unwrap();
+        }
+        let key = |kv: Result<KV, Error>| {
+            let kv = kv.unwrap();
+            assert_eq!(vec![kv.key[3]; 10_000], kv.value);
+            u32::from_be_bytes(kv.key.try_into().unwrap())
+        };
+
+        let all = RangeQuery::new(Unbounded, Unbounded);
+        assert!(storage.iter(&all).map(key).eq(0..2000));
+        let query = RangeQuery::new(Included(100u32.to_be_bytes().to_vec()), Unbounded)
+            .reverse(true)
+            .limit(700);
+        assert!(storage.iter(&query).map(key).eq((1300..2000).rev()));
+        let query = RangeQuery::new(Unbounded, Excluded(1500u32.to_be_bytes().to_vec()))
+            .after(999u32.to_be_bytes().to_vec());
+        assert!(storage.iter(&query).map(key).eq(1000..1500));
+
+        // an iterator keeps the files it reads until it is dropped, even when left unfinished
+        let mut iter = storage.iter(&all);
+        assert_eq!(Some(0), iter.next().map(key));
+        for i in 0..2000u32 {
+            storage.delete(&i.to_be_bytes()).unwrap();
+        }
+        storage.merge_all().unwrap();
+        let files_while_pinned = written_data_files(data_dir).len();
+        assert!(iter.by_ref().take(10).map(key).eq(1..11));
+        assert_eq!(0, storage.iter(&all).count());
+        drop(iter);
+        assert!(written_data_files(data_dir).len() < files_while_pinned);
+        drop(storage);
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn range_iter_prefetch_test() {
+        let data_dir = "test-data-range-iter-prefetch";
+        let _ = fs::remove_dir_all(data_dir);
+        let options = BitCaskOptions::new()
+            .max_file_size(2000)
+            .io_threads(1)
+            .background_merge(false);
+        let mut storage = open(data_dir, options).unwrap();
+        for i in 0..600u32 {
+            storage
+                .put(i.to_be_bytes().to_vec(), i.to_string().into_bytes())
+                .unwrap();
+        }
+
+        // read in batches of 256 keys, the next batch is requested before the current one is
+        // consumed, up to the last one
+        let all = RangeQuery::new(Unbounded, Unbounded);
+        let mut iter = storage.snapshot().iter(&all);
+        assert!(!iter.prefetching());
+        for i in 0..600u32 {
+            let kv = iter.next().unwrap().unwrap();
+            assert_eq!(i.to_string().into_bytes(), kv.value);
+            assert_eq!(i < 512, iter.prefetching());
+        }
+        assert!(iter.next().is_none());
+        drop(iter);
+        drop(storage);
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    // Threads of the process, including the ones of tests running alongside
+    #[cfg(target_os = "linux")]
+    fn thread_count() -> usize {
+        fs::read_dir("/proc/self/task").unwrap().count()
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn range_iter_threads_test() {
+        let data_dir = "test-data-range-iter-threads";
+        let _ = fs::remove_dir_all(data_dir);
+        let options = BitCaskOptions::new()
+            .max_file_size(2000)
+            .io_threads(2)
+            .background_merge(false);
+        let mut storage = open(data_dir, options).unwrap();
+        for i in 0..1000u32 {
+            storage
+                .put(i.to_be_bytes().to_vec(), i.to_string().into_bytes())
+                .unwrap();
+        }
+
+        // many iterators open at once, from several threads, start no threads of their own
+        let before = thread_count();
+        let opened = Arc::new(Barrier::new(5));
+        let counted = Arc::new(Barrier::new(5));
+        let consumers: Vec<_> = (0..4)
+            .map(|_| {
+                let (storage, opened, counted) = (storage.clone(), opened.clone(), counted.clone());
+                thread::spawn(move || {
+                    let all = RangeQuery::new(Unbounded, Unbounded);
+                    let mut iters: Vec<_> = (0..32).map(|_| storage.iter(&all)).collect();
+                    for iter in &mut iters {
+                        assert_eq!(
+                            0u32.to_be_bytes().to_vec(),
+                            iter.next().unwrap().unwrap().key
+                        );
+                    }
+                    opened.wait();
+                    counted.wait();
+                    for iter in iters {
+                        assert_eq!(999, iter.count());
+                    }
+                })
+            })
+            .collect();
+        opened.wait();
+        assert!(thread_count() < before + 4 + 32);
+        counted.wait();
+        for consumer in consumers {
+            consumer.join().unwrap();
+        }
+        drop(storage);
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn io_threads_test() {
+        let data_dir = "test-data-io-threads";
+        // on the calling thread, on a single pool thread, and on the default pool
+        let options = [
+            BitCaskOptions::new().io_threads(0),
+            BitCaskOptions::new().io_threads(1),
+            BitCaskOptions::new(),
+        ];
+        for options in options {
+            let _ = fs::remove_dir_all(data_dir);
+            let options = options.max_file_size(2000).background_merge(false);
+            let mut storage = open(data_dir, options).unwrap();
+            for i in 0..500u32 {
+                storage
+                    .put(i.to_be_bytes().to_vec(), i.to_string().into_bytes())
+                    .unwrap();
+            }
+            let key_dir = storage.key_dir_weak();
+
+            // concurrent ranges share the threads of the store
+            let readers: Vec<_> = (0..4u32)
+                .map(|r| {
+                    let storage = storage.clone();
+                    thread::spawn(move || {
+                        for _ in 0..20 {
+                            let start = (r * 100).to_be_bytes();
+                            let end = (r * 100 + 149).to_be_bytes();
+                            let range = storage.range(&start, &end).unwrap();
+                            assert_eq!(150, range.len());
+                            for (i, kv) in (r * 100..).zip(&range) {
+                                assert_eq!(i.to_string().into_bytes(), kv.value);
+                            }
+                        }
+                    })
+                })
+                .collect();
+            for reader in readers {
+                reader.join().unwrap();
+            }
+            let all = RangeQuery::new(Unbounded, Unbounded).reverse(true);
+            assert!(storage
+                .iter(&all)
+                .map(|kv| kv.unwrap().key)
+                .eq((0..500u32).rev().map(|i| i.to_be_bytes().to_vec())));
+
+            // neither the pool nor the iterator keep the store once it is dropped
+            drop(storage);
+            assert!(key_dir.upgrade().is_none());
+        }
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    // Values are the time they were made at, in nanoseconds
+    fn timing_records(record_count: usize) -> Vec<KV> {
         let mut records = Vec::with_capacity(record_count);
         for i in 1..record_count {
             let v = SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
                 .as_nanos()
-                .to_string();
-            records.push(KV { key: i, value: v })
+                .to_string()
+                .into_bytes();
+            records.push(KV {
+                key: i.to_be_bytes().to_vec(),
+                value: v,
+            })
         }
+        records
+    }
+
+    #[test]
+    fn timing_bulk_insert() {
+        // 1_000_000_000 exceeds memory available
+        // 1_000_000 takes about 4.60 seconds
+        // benchmark shows around 3500 ns/iteration
+        let record_count = 1_000_000;
+        let records = timing_records(record_count);
 
-        use std::time::Instant;
         let now = Instant::now();
-        let storage = new_bit_cask(DATA_DIR);
+        // a single batch of about 63 MB, over the default maximum
+        let options = BitCaskOptions::new().max_batch_size(u64::MAX);
+        let storage = open("test-data-timing-bulk-insert", options);
         assert!(storage.is_ok());
         let mut storage = storage.unwrap();
         let put_result = storage.batch_put(records);
@@ -92,20 +1485,35 @@ mod tests {
         assert!(put_result.is_ok());
     }
 
+    #[test]
+    fn timing_bulk_load() {
+        let record_count = 1_000_000;
+        let records = timing_records(record_count);
+
+        let now = Instant::now();
+        let storage = new_bit_cask("test-data-timing-bulk-load");
+        assert!(storage.is_ok());
+        let mut storage = storage.unwrap();
+        let put_result = storage.bulk_load(records);
+
+        let elapsed = now.elapsed();
+        println!("Loaded {} records. Elapsed: {:.2?}", record_count, elapsed);
+        assert!(put_result.is_ok());
+    }
+
     #[test]
     fn timing_single_insert() {
         // 1_000_000 takes about 6 seconds
         // 10_000_000 takes about 8.76s
         // benchmark shows around 3500 ns/iteration
-        let record_count = 1_000_000;
-        use std::time::Instant;
+        let record_count: usize = 1_000_000;
         let now = Instant::now();
-        let storage = new_bit_cask(DATA_DIR);
+        let storage = new_bit_cask("test-data-timing-single-insert");
         assert!(storage.is_ok());
         let mut storage = storage.unwrap();
 
         for i in 1..record_count {
-            let _ = storage.put(i, "my-value".to_string());
+            let _ = storage.put(i.to_be_bytes().to_vec(), b"my-value".to_vec());
         }
 
         let elapsed = now.elapsed();
@@ -121,7 +1529,7 @@ mod tests {
         let value = r#"{"_id":"67af531e45a1886ffae3a157","index":0,"guid":"9df745eb-d74d-4edb-975f-36effe080bb5","isActive":true,"balance":"$1,548.07","picture":"http://placehold.it/32x32","age":25,"eyeColor":"blue","name":"Mccarthy Garcia","gender":"male","company":"KYAGORO","email":"mccarthygarcia@kyagoro.com","phone":"+1 (868) 539-3028","address":"122 Kansas Place, Venice, Maryland, 499","about":"Deserunt aliquip elit minim labore nostrud deserunt fugiat ipsum ea occaecat voluptate ipsum eu. Amet labore fugiat ad cupidatat occaecat eu elit laboris aute. Deserunt ullamco officia consectetur consequat adipisicing amet eiusmod ex amet. Quis sit culpa eiusmod id qui pariatur ea culpa sunt deserunt. Sit eu fugiat nulla esse ea nisi mollit laborum in.\r\n","registered":"2016-12-06T06:18:13 -00:00","latitude":39.945306,"longitude":27.872656,"tags":["minim","aliqua","occaecat","eiusmod","anim","nisi","veniam"],"friends":[{"id":0,"name":"Gibson Bowman"},{"id":1,"name":"Davidson Forbes"},{"id":2,"name":"Meadows Doyle"}],"greeting":"Hello, Mccarthy Garcia! You have 6 unread messages.","favoriteFruit":"apple"}"#;
         // took about 11 minutes and inserted around 52GB of data
         // Max memory used was 7 GB
-        let record_count = 50_000_000;
+        let record_count: usize = 50_000_000;
         let batch_size = 1_000_000;
         let storage = new_bit_cask(DATA_DIR);
         assert!(storage.is_ok());
@@ -130,7 +1538,7 @@ mod tests {
         let mut total = 0;
         for j in 0..(record_count / batch_size) {
             for i in total..(batch_size * (j + 1)) {
-                let _ = storage.put(i, value.to_string());
+                let _ = storage.put(i.to_be_bytes().to_vec(), value.as_bytes().to_vec());
             }
             total += batch_size;
             // Better mimic behaviour of getting multiple requests over a period of time
@@ -1,38 +1,400 @@
-use crate::storage::data_files::{create_new_active_file, create_new_file, delete_file, save};
-use crate::storage::{KVStorage, KV};
-use std::cmp::min;
+use crate::storage::data_files::{
+    batch_size, check_record, decode_header, delete_file, is_expired, lock_data_dir, save,
+    sync_file, truncate_file, unix_millis, ReadHandle, ReadHandles, RecordInfo, SequencedRecord,
+    BATCH_BEGIN, BATCH_COMMIT, DEFAULT_MAX_OPEN_FILES, HEADER_SIZE, NO_EXPIRY, TOMBSTONE,
+};
+use crate::storage::hint_files::{
+    delete_hint_file, has_hint_file, is_compacted, read_hint_file, write_hint_file,
+};
+use crate::storage::io_pool::IoPool;
+use crate::storage::key_dir::{FileTable, Key, KeyDir, TOMBSTONE_LENGTH};
+use crate::storage::manifest::Manifest;
+use crate::storage::paged_index::{
+    new_index_dir, remove_stale_indexes, PagedIndex, DEFAULT_BUFFERED_KEYS,
+};
+use crate::storage::snapshots::{Changes, Snapshots};
+use crate::storage::value_cache::ValueCache;
+use crate::storage::{Condition, KVIter, KVStorage, RangePage, RangeQuery, KV};
+use std::cmp::{max, min};
 use std::collections::{BTreeMap, HashMap, HashSet};
-use std::fs::{File, OpenOptions};
-use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
-use std::path::Path;
-use std::sync::{mpsc, Arc, Mutex};
-use std::thread::available_parallelism;
-use std::time::Duration;
-use std::{fs, thread};
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Read, Seek, SeekFrom};
+use std::ops::Bound;
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread::{available_parallelism, JoinHandle};
+use std::time::{Duration, SystemTime};
+use std::{env, fs, thread};
 
-const HINT_FILE_NAME: &str = "hint-file";
+const DEFAULT_MAX_FILE_SIZE: u64 = 10_000_000;
+const DEFAULT_MAX_BATCH_SIZE: u64 = 16_000_000;
+const DEFAULT_MERGE_INTERVAL: Duration = Duration::from_secs(60);
 
-#[derive(Clone)]
-struct Key {
-    filename: String,
-    timestamp: u64,
-    name: usize,
-    offset: u64,
-    length: usize,
+// Bytes of the records in a data file that the key dir points to, and of the ones it does not
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct FileStats {
+    pub(crate) live_bytes: u64,
+    pub(crate) dead_bytes: u64,
+}
+
+impl FileStats {
+    // a file without live records, even an empty one, is all garbage
+    fn dead_ratio(&self) -> f64 {
+        if self.live_bytes == 0 {
+            return 1.0;
+        }
+        self.dead_bytes as f64 / (self.live_bytes + self.dead_bytes) as f64
+    }
+}
+
+// Counters of the value cache, to size it
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    // values held, and the bytes of their keys and values
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+// A data file is compacted once either threshold is crossed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CompactionThresholds {
+    // share of the file taken by overwritten or deleted records, from 0 to 1
+    pub dead_ratio: f64,
+    // bytes taken by overwritten or deleted records
+    pub dead_bytes: u64,
+}
+
+impl Default for CompactionThresholds {
+    fn default() -> Self {
+        CompactionThresholds {
+            dead_ratio: 0.5,
+            // half of a full data file
+            dead_bytes: 5_000_000,
+        }
+    }
+}
+
+// When written data is flushed from the OS page cache to the disk
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SyncPolicy {
+    // every write is synced before it is acknowledged
+    EveryWrite,
+    // a background job syncs the active file every given number of milliseconds
+    EveryMillis(u64),
+    // the OS decides when to flush. A power loss can drop acknowledged writes
+    #[default]
+    OsManaged,
+}
+
+// Parses the `sync` argument: "every-write", "os", or a number of milliseconds above 0
+impl FromStr for SyncPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "every-write" => Ok(SyncPolicy::EveryWrite),
+            "os" => Ok(SyncPolicy::OsManaged),
+            // 0 would keep the sync job from ever waiting
+            _ => match s.parse() {
+                Ok(millis) if millis > 0 => Ok(SyncPolicy::EveryMillis(millis)),
+                _ => Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid sync policy {}", s),
+                )),
+            },
+        }
+    }
 }
 
 #[derive(Clone, Default)]
 pub struct BitCask {
     pub(crate) data_dir: String,
-    active_dir: String,
-    key_dir: Arc<Mutex<BTreeMap<usize, Key>>>,
+    // shared so every clone appends to, and compaction skips, the same active file
+    active_dir: Arc<Mutex<String>>,
+    key_dir: Arc<Mutex<KeyDir>>,
+    // locked after the key dir, and updated along with it
+    file_stats: Arc<Mutex<HashMap<u32, FileStats>>>,
+    // locked last, and only for lookups
+    files: Arc<Mutex<FileTable>>,
+    // open files to read from. Never locked along with the file table
+    read_handles: Arc<ReadHandles>,
+    // locked last, after the key dir
+    value_cache: Option<Arc<ValueCache>>,
+    // locked last, after the key dir and the file table
+    snapshots: Arc<Mutex<Snapshots>>,
+    // sequence number of the next record written. Taken with the active file lock held, so
+    // numbers grow in the order records are appended
+    next_seq: Arc<AtomicU64>,
+    // locked last, when files are created or deleted
+    manifest: Arc<Mutex<Manifest>>,
+    // threads for range reads, stopped with the last handle
+    io_pool: Arc<IoPool>,
+    // stopped when the last handle is dropped, before the data dir is unlocked
+    workers: Option<Arc<Workers>>,
+    // lock on the data dir, released once every handle of the store is dropped
+    _dir_lock: Option<Arc<File>>,
+    // held while compacting, so two merges never pick the same files
+    merging: Arc<Mutex<()>>,
+    options: BitCaskOptions,
+}
+
+// The background jobs of a store. They wait on `stop` between runs, so stopping them never
+// interrupts a merge halfway
+struct Workers {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    handles: Mutex<Vec<JoinHandle<()>>>,
+    active_dir: Arc<Mutex<String>>,
+    runs: Arc<(Mutex<JobRuns>, Condvar)>,
+}
+
+// Runs of the background jobs so far, which tests wait on instead of sleeping
+#[derive(Default)]
+struct JobRuns {
+    merges: u64,
+    syncs: u64,
+}
+
+fn count_run(runs: &(Mutex<JobRuns>, Condvar), count: impl FnOnce(&mut JobRuns)) {
+    let (counts, changed) = runs;
+    count(&mut counts.lock().unwrap());
+    changed.notify_all();
+}
+
+impl Workers {
+    // Stops the jobs, waiting for a running merge to finish, and syncs the active file.
+    // Calling it again only syncs
+    fn shutdown(&self) -> Result<(), Error> {
+        let (stopped, wake) = &*self.stop;
+        *stopped.lock().unwrap() = true;
+        wake.notify_all();
+        for handle in self.handles.lock().unwrap().drain(..) {
+            if handle.join().is_err() {
+                println!("A background job panicked");
+            }
+        }
+        let active_dir = self.active_dir.lock().unwrap();
+        sync_file(&active_dir)
+    }
+}
+
+impl Drop for Workers {
+    fn drop(&mut self) {
+        if let Err(e) = self.shutdown() {
+            println!("Error closing the store: {:?}", e);
+        }
+    }
+}
+
+// Waits for the interval, or until the jobs are stopped. Returns whether they were
+fn wait_for_stop(stop: &(Mutex<bool>, Condvar), interval: Duration) -> bool {
+    let (stopped, wake) = stop;
+    let guard = stopped.lock().unwrap();
+    let (guard, _) = wake
+        .wait_timeout_while(guard, interval, |stopped| !*stopped)
+        .unwrap();
+    *guard
+}
+
+// Where the key dir is kept
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum KeyIndex {
+    // all keys in memory, the fastest
+    #[default]
+    Memory,
+    // in a paged index file in the data dir, for more keys than fit in memory. The pages read
+    // are cached, up to `cache_bytes`
+    Paged {
+        cache_bytes: usize,
+    },
+}
+
+// How a store is opened. E.g.:
+// `open("data", BitCaskOptions::new().max_file_size(1_000_000).background_merge(false))`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BitCaskOptions {
+    max_file_size: u64,
+    merge_interval: Duration,
+    compaction_thresholds: CompactionThresholds,
+    background_merge: bool,
+    read_only: bool,
+    sync_policy: SyncPolicy,
+    key_index: KeyIndex,
+    max_open_files: usize,
+    mmap_reads: bool,
+    value_cache_bytes: usize,
+    io_threads: Option<usize>,
+    max_batch_size: u64,
+}
+
+impl Default for BitCaskOptions {
+    fn default() -> Self {
+        BitCaskOptions {
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            merge_interval: DEFAULT_MERGE_INTERVAL,
+            compaction_thresholds: Default::default(),
+            background_merge: true,
+            read_only: false,
+            sync_policy: Default::default(),
+            key_index: Default::default(),
+            max_open_files: DEFAULT_MAX_OPEN_FILES,
+            mmap_reads: false,
+            value_cache_bytes: 0,
+            io_threads: None,
+            max_batch_size: DEFAULT_MAX_BATCH_SIZE,
+        }
+    }
+}
+
+impl BitCaskOptions {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // Size after which the active file is sealed and a new one started
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = bytes;
+        self
+    }
+
+    // Time between two runs of the background compaction
+    pub fn merge_interval(mut self, interval: Duration) -> Self {
+        self.merge_interval = interval;
+        self
+    }
+
+    pub fn compaction_thresholds(mut self, thresholds: CompactionThresholds) -> Self {
+        self.compaction_thresholds = thresholds;
+        self
+    }
+
+    // Without it, files are only compacted by calling `merge` or `merge_all`
+    pub fn background_merge(mut self, enabled: bool) -> Self {
+        self.background_merge = enabled;
+        self
+    }
+
+    // A read only store never changes the data dir. Writes and compactions fail
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    // `open` rejects `EveryMillis(0)`, as parsing does
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    pub fn key_index(mut self, key_index: KeyIndex) -> Self {
+        self.key_index = key_index;
+        self
+    }
+
+    // How many data files are kept open for reads
+    pub fn max_open_files(mut self, max_open_files: usize) -> Self {
+        self.max_open_files = max_open_files;
+        self
+    }
+
+    // Reads sealed files through memory maps instead of system calls. The active file is
+    // still read with calls, as it grows. Only on unix, other platforms ignore it
+    pub fn mmap_reads(mut self, enabled: bool) -> Self {
+        self.mmap_reads = enabled;
+        self
+    }
+
+    // Keeps the values read last in memory, up to the bytes of their keys and values. 0, the
+    // default, disables the cache
+    pub fn value_cache_bytes(mut self, bytes: usize) -> Self {
+        self.value_cache_bytes = bytes;
+        self
+    }
+
+    // Largest batch, in bytes as written, that `write_batch` and `batch_put` accept. A batch is
+    // written to a single file, so it can take that file past `max_file_size`, and recovery
+    // holds a whole batch in memory until its commit marker. `bulk_load` writes larger loads
+    pub fn max_batch_size(mut self, bytes: u64) -> Self {
+        self.max_batch_size = bytes;
+        self
+    }
+
+    // Threads reading data files for range reads, shared by all of them. By default one less
+    // than the available parallelism, and at least one. With 0, ranges read on the calling
+    // thread, one file at a time
+    pub fn io_threads(mut self, threads: usize) -> Self {
+        self.io_threads = Some(threads);
+        self
+    }
+}
+
+// Puts and deletes applied together by `write_batch`. They are written to a single data file,
+// between a begin and a commit marker, and recovery skips a batch without its commit marker,
+// so a crash never leaves part of a batch on disk. Later changes to a key win over earlier ones
+#[derive(Default)]
+pub struct WriteBatch {
+    records: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.records.push((key, Some(value)));
+    }
+
+    pub fn delete(&mut self, key: Vec<u8>) {
+        self.records.push((key, None));
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
 }
 
 pub fn new_bit_cask(data_dir: &str) -> Result<BitCask, Error> {
+    open(data_dir, Default::default())
+}
+
+pub fn open(data_dir: &str, options: BitCaskOptions) -> Result<BitCask, Error> {
+    if options.sync_policy == SyncPolicy::EveryMillis(0) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Invalid sync policy, the interval must be above 0 milliseconds",
+        ));
+    }
+    if !options.read_only {
+        fs::create_dir_all(data_dir)?;
+    }
+    let dir_lock = lock_data_dir(data_dir, options.read_only)?;
+    let manifest = Manifest::open(data_dir, options.read_only)?;
     let mut bc = BitCask {
         data_dir: data_dir.to_string(),
         active_dir: Default::default(),
         key_dir: Arc::new(Mutex::new(Default::default())),
+        file_stats: Default::default(),
+        files: Default::default(),
+        read_handles: Arc::new(ReadHandles::new(options.max_open_files, options.mmap_reads)),
+        snapshots: Default::default(),
+        value_cache: (options.value_cache_bytes > 0)
+            .then(|| Arc::new(ValueCache::new(options.value_cache_bytes))),
+        next_seq: Default::default(),
+        manifest: Arc::new(Mutex::new(manifest)),
+        io_pool: Arc::new(IoPool::new(io_threads(options.io_threads)?)),
+        workers: None,
+        _dir_lock: Some(Arc::new(dir_lock)),
+        merging: Default::default(),
+        options,
     };
 
     bc.init()?;
@@ -41,458 +403,1446 @@ pub fn new_bit_cask(data_dir: &str) -> Result<BitCask, Error> {
 }
 
 impl KVStorage for BitCask {
-    fn get(&self, key: usize) -> Result<String, Error> {
-        let kd = self.key_dir.lock().unwrap();
-        let k = kd.get(&key);
+    fn get(&self, key: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(self.get_versioned(key)?.map_or(vec![], |(value, _)| value))
+    }
+
+    // The version of a key is the sequence number of its record. Sequence numbers are never
+    // reused, and compaction copies keep them, so it only changes when the key is written
+    fn get_versioned(&self, key: &[u8]) -> Result<Option<(Vec<u8>, u64)>, Error> {
+        let mut kd = self.key_dir.lock().unwrap();
+        let k = kd.get(key)?.filter(|k| !k.is_tombstone());
         match k {
             Some(k) => {
-                let result = read_from_file(
-                    k.filename.clone(),
-                    vec![Key {
-                        filename: "".to_string(),
-                        name: k.name,
-                        offset: k.offset,
-                        length: k.length,
-                        timestamp: k.timestamp,
-                    }],
-                )?;
-                let (_, v) = result.first().unwrap();
-                Ok(v.to_string())
-            }
-            None => Ok("".to_string()),
-        }
-    }
-
-    fn put(&mut self, key: usize, value: String) -> Result<(), Error> {
-        let result = save(&self.data_dir, &self.active_dir, vec![(key, value)]);
-        match result {
-            Ok((r, active_dir)) => {
-                self.active_dir = active_dir;
-                let (dir, offset, length, ts) = r.first().unwrap();
-                let mut kd = self.key_dir.lock().unwrap();
-                kd.insert(
-                    key,
-                    Key {
-                        filename: dir.to_string(),
-                        timestamp: *ts,
-                        name: key,
-                        offset: *offset,
-                        length: *length,
-                    },
-                );
-                Ok(())
+                let now = unix_millis(SystemTime::now());
+                let cached = self
+                    .value_cache
+                    .as_ref()
+                    .and_then(|c| c.get(key, k.seq, now));
+                if let Some(value) = cached {
+                    return Ok(Some((value, k.seq)));
+                }
+                let file = self.read_handle(k.file_id)?;
+                let result = read_from_file(&file, vec![(key.to_vec(), k)])?;
+                let (kv, expires_at) = result.into_iter().next().unwrap();
+                // an expired key is left in the key dir until a compaction drops it
+                if is_expired(expires_at, now) {
+                    return Ok(None);
+                }
+                if let Some(cache) = &self.value_cache {
+                    cache.insert(key.to_vec(), k.seq, expires_at, kv.value.clone());
+                }
+                Ok(Some((kv.value, k.seq)))
             }
-            Err(e) => Err(e),
+            None => Ok(None),
         }
     }
 
-    fn delete(&mut self, key: usize) -> Result<(), Error> {
-        let mut kd = self.key_dir.lock().unwrap();
-        kd.remove(&key);
-        Ok(())
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Error> {
+        self.write(
+            vec![(key, Some(value))],
+            NO_EXPIRY,
+            self.options.sync_policy,
+        )
     }
 
-    fn range(&self, start: usize, end: usize) -> Result<Vec<KV>, Error> {
-        // Group keys by filename
-        let mut grouped_keys: HashMap<String, Vec<Key>> = HashMap::new();
-        let kd = self.key_dir.lock().unwrap();
-        for (_, value) in kd.range(start..=end) {
-            grouped_keys
-                .entry(value.filename.clone())
-                .or_insert_with(Vec::new)
-                .push(Key {
-                    filename: value.filename.clone(),
-                    name: value.name,
-                    offset: value.offset,
-                    length: value.length,
-                    timestamp: value.timestamp,
-                });
+    fn delete(&mut self, key: &[u8]) -> Result<(), Error> {
+        if self
+            .key_dir
+            .lock()
+            .unwrap()
+            .get(key)?
+            .is_none_or(|k| k.is_tombstone())
+        {
+            return Ok(());
         }
+        // the tombstone keeps the key deleted when the key dir is rebuilt from the data files
+        self.write(
+            vec![(key.to_vec(), None)],
+            NO_EXPIRY,
+            self.options.sync_policy,
+        )
+    }
 
-        let default_parallelism_approx =
-            min(available_parallelism()?.get() - 1, grouped_keys.len());
-        let (gk_tx, gk_rx) = mpsc::channel();
-        let rx = Arc::new(Mutex::new(gk_rx));
-        let (r_tx, r_rx) = mpsc::channel();
-        let mut handles: Vec<thread::JoinHandle<Result<(), Error>>> = vec![];
-
-        for _ in 0..default_parallelism_approx {
-            let tx = r_tx.clone();
-            let rx = Arc::clone(&rx);
-            let handle = thread::spawn(move || {
-                while let Ok((filename, keys)) = rx.lock().unwrap().recv() {
-                    let result = read_from_file(filename, keys)?;
-                    result.iter().for_each(|(k, v)| {
-                        tx.send(KV {
-                            key: *k,
-                            value: v.to_string(),
-                        })
-                        .unwrap();
-                    });
-                }
-                return Ok(());
-            });
-            handles.push(handle);
-        }
+    // Reads from a snapshot, so the key dir is only locked while the keys are listed
+    fn range(&self, start: &[u8], end: &[u8]) -> Result<Vec<KV>, Error> {
+        self.snapshot().range(start, end)
+    }
 
-        for g in grouped_keys {
-            gk_tx.send(g).unwrap();
-        }
-        drop(gk_tx);
-        drop(r_tx);
+    fn scan(&self, query: &RangeQuery) -> Result<RangePage, Error> {
+        self.snapshot().scan(query)
+    }
 
-        for handle in handles {
-            handle.join().unwrap()?;
-        }
+    fn iter(&self, query: &RangeQuery) -> KVIter {
+        Box::new(self.snapshot().iter(query))
+    }
 
-        let mut results = vec![];
-        for kv in r_rx {
-            results.push(kv);
-        }
+    fn batch_put(&mut self, kvs: Vec<KV>) -> Result<(), Error> {
+        self.batch_put_with_sync_policy(kvs, self.options.sync_policy)
+    }
 
-        Ok(results)
+    fn put_if(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        condition: &Condition,
+    ) -> Result<bool, Error> {
+        self.write_if(key, Some(value), condition)
     }
 
-    fn batch_put(&mut self, kvs: Vec<KV>) -> Result<(), Error> {
-        let data_vec: Vec<(usize, String)> =
-            kvs.iter().map(|kv| (kv.key, kv.value.clone())).collect();
-        let (results, active_dir) = save(&self.data_dir, &self.active_dir, data_vec)?;
-        self.active_dir = active_dir;
+    // A key that is not set is left as it is, with nothing to delete
+    fn delete_if(&mut self, key: &[u8], condition: &Condition) -> Result<bool, Error> {
+        self.write_if(key.to_vec(), None, condition)
+    }
+}
 
-        let mut kd = self.key_dir.lock().unwrap();
-        for (kv, (dir, offset, length, ts)) in kvs.into_iter().zip(results) {
-            kd.insert(
-                kv.key,
-                Key {
-                    filename: dir,
-                    timestamp: ts,
-                    name: kv.key,
-                    offset,
-                    length,
-                },
+// The size of the I/O pool, see `BitCaskOptions::io_threads`
+fn io_threads(threads: Option<usize>) -> Result<usize, Error> {
+    match threads {
+        Some(threads) => Ok(threads),
+        None => Ok(max(available_parallelism()?.get(), 2) - 1),
+    }
+}
+
+// A data file, and keys to read from it
+type FileKeys = (Arc<ReadHandle>, Vec<(Vec<u8>, Key)>);
+
+// Reads of files queued on the pool, see `start_read`
+struct PendingRead {
+    results: mpsc::Receiver<Result<Vec<(KV, u64)>, Error>>,
+    files: usize,
+    now: u64,
+}
+
+// Queues the reads of the keys grouped by file, several files at a time on the threads of the
+// pool, and returns without waiting for them
+fn start_read(pool: &IoPool, grouped_keys: Vec<FileKeys>, now: u64) -> PendingRead {
+    let files = grouped_keys.len();
+    let (tx, rx) = mpsc::channel();
+    for (file, keys) in grouped_keys {
+        let tx = tx.clone();
+        pool.execute(move || {
+            // the reader is gone once another file failed, or the read was dropped
+            let _ = tx.send(read_from_file(&file, keys));
+        });
+    }
+    PendingRead {
+        results: rx,
+        files,
+        now,
+    }
+}
+
+impl PendingRead {
+    // Waits for the reads of every file. Expired keys are left out
+    fn wait(self) -> Result<Vec<KV>, Error> {
+        let mut results = vec![];
+        let mut read = 0;
+        for result in self.results {
+            results.extend(
+                result?
+                    .into_iter()
+                    .filter(|(_, expires_at)| !is_expired(*expires_at, self.now))
+                    .map(|(kv, _)| kv),
             );
+            read += 1;
         }
-
-        Ok(())
+        // a job that panicked sends nothing
+        if read < self.files {
+            return Err(Error::other("A data file read did not finish"));
+        }
+        Ok(results)
     }
 }
 
 impl BitCask {
     fn init(&mut self) -> Result<(), Error> {
-        let path = Path::new(&self.data_dir);
-        fs::create_dir_all(path)?;
-
-        println!("Creating new active data file...");
-        self.active_dir = create_new_active_file(&self.data_dir)?;
+        let read_only = self.options.read_only;
+        let (data_files, last_file) = {
+            let mut manifest = self.manifest.lock().unwrap();
+            let last_file = manifest.active_file();
+            if read_only {
+                println!("Opening {} as read only...", self.data_dir);
+            } else {
+                manifest.remove_stray_files()?;
+                println!("Creating new active data file...");
+                *self.active_dir.lock().unwrap() = manifest.new_active_file()?;
+            }
+            (manifest.data_files(), last_file)
+        };
+        let active_dir = self.active_dir.lock().unwrap().clone();
+        let sealed_files: Vec<String> = data_files
+            .iter()
+            .filter(|f| **f != active_dir)
+            .cloned()
+            .collect();
 
         println!("Building key dir from existing data...");
-        let keys = compute_key_dir(&self.data_dir, &self.active_dir)?;
+        let mut keys = match self.options.key_index {
+            KeyIndex::Memory => KeyDir::default(),
+            KeyIndex::Paged { cache_bytes } => {
+                // the index of a read only store goes to the temporary dir instead
+                let parent = if read_only {
+                    env::temp_dir().to_string_lossy().to_string()
+                } else {
+                    remove_stale_indexes(&self.data_dir)?;
+                    self.data_dir.clone()
+                };
+                let dir = new_index_dir(&parent);
+                KeyDir::Paged(PagedIndex::new(&dir, cache_bytes, DEFAULT_BUFFERED_KEYS)?)
+            }
+        };
+        let mut files = FileTable::default();
+        let next_seq = compute_key_dir(sealed_files, last_file, !read_only, &mut keys, &mut files)?;
+        // versions must not be reused, even for keys whose records were compacted away
+        let next_seq = max(next_seq, self.manifest.lock().unwrap().next_seq());
+        let file_stats = compute_file_stats(&data_files, &mut keys, &mut files)?;
+        if !read_only {
+            self.read_handles.set_active_file(files.id(&active_dir));
+        }
         self.key_dir = Arc::new(Mutex::new(keys));
+        self.file_stats = Arc::new(Mutex::new(file_stats));
+        self.files = Arc::new(Mutex::new(files));
+        self.next_seq = Arc::new(AtomicU64::new(next_seq));
 
-        let data_dir = self.data_dir.clone();
-        let active_dir = self.active_dir.clone();
-        let key_dir = Arc::clone(&self.key_dir);
-        let compact = move |data_dir: String,
-                            active_dir: String,
-                            key_dir: Arc<Mutex<BTreeMap<usize, Key>>>| {
-            let data_dir = data_dir.clone();
-            let active_dir = active_dir.clone();
-            // killed off when main program finishes
-            loop {
-                let key_dir = Arc::clone(&key_dir);
-                thread::sleep(Duration::from_secs(60));
-                println!("compaction starting...");
-                // copy key_dir to avoid locking other processes
-                let cloned_key_dir = {
-                    let key_dir_guard = key_dir.lock().unwrap();
-                    key_dir_guard.clone()
-                };
-                let r = compact_files(&*data_dir, cloned_key_dir);
-                if r.is_err() {
-                    println!("Error compacting: {:?}", r.err().unwrap());
-                    return;
-                }
-                let new_key_dir = r.unwrap();
-                println!("new compacted key_dir created!. Creating hint file...");
-                let chr = create_hint_file(&*data_dir, new_key_dir.clone());
-                if chr.is_err() {
-                    println!("Error creating hint file: {:?}", chr.err().unwrap());
-                    return;
-                }
-                println!("hint file created! Updating keys in memory...");
-                {
-                    let mut key_dir_guard = key_dir.lock().unwrap();
-                    for (k, v) in new_key_dir {
-                        key_dir_guard.insert(k, v);
+        if read_only {
+            println!("key dir created. Ready!");
+            return Ok(());
+        }
+
+        let workers = Workers {
+            stop: Default::default(),
+            handles: Default::default(),
+            active_dir: Arc::clone(&self.active_dir),
+            runs: Default::default(),
+        };
+        let mut handles = workers.handles.lock().unwrap();
+        if self.options.background_merge {
+            // the clone has no workers of its own, so the job does not keep itself running
+            let bit_cask = self.clone();
+            let stop = Arc::clone(&workers.stop);
+            let runs = Arc::clone(&workers.runs);
+            let interval = self.options.merge_interval;
+            handles.push(thread::spawn(move || {
+                while !wait_for_stop(&stop, interval) {
+                    let r = bit_cask.merge();
+                    if r.is_err() {
+                        println!("Error compacting: {:?}", r.err().unwrap());
+                        return;
                     }
+                    count_run(&runs, |runs| runs.merges += 1);
+                    println!("compaction done. Sleeping for {:?}", interval);
                 }
-                println!("Key dir updated! Deleting old files...");
-                let dfr = delete_old_files(&*data_dir, &*active_dir, key_dir);
-                if dfr.is_err() {
-                    println!("Error deleting old files: {:?}", dfr.err().unwrap());
-                    return;
+            }));
+        }
+
+        if let SyncPolicy::EveryMillis(interval) = self.options.sync_policy {
+            let active_dir = Arc::clone(&self.active_dir);
+            let stop = Arc::clone(&workers.stop);
+            let runs = Arc::clone(&workers.runs);
+            handles.push(thread::spawn(move || {
+                while !wait_for_stop(&stop, Duration::from_millis(interval)) {
+                    // files sealed in between were already synced on rollover
+                    let filename = active_dir.lock().unwrap().clone();
+                    if let Err(e) = sync_file(&filename) {
+                        println!("Error syncing {}: {:?}", filename, e);
+                    }
+                    count_run(&runs, |runs| runs.syncs += 1);
                 }
-                println!("compaction done. Sleeping for 10 sec");
-            }
-        };
-        thread::spawn(move || compact(data_dir, active_dir, key_dir));
+            }));
+        }
+        drop(handles);
+        self.workers = Some(Arc::new(workers));
 
         println!("key dir created. Ready!");
         Ok(())
     }
-}
 
-fn read_from_file(filename: String, keys: Vec<Key>) -> Result<Vec<(usize, String)>, Error> {
-    let mut file = File::open(filename)?;
-    let mut results = Vec::new();
+    // Stops the background jobs, once a running merge finishes, and syncs the active file.
+    // Other handles of the store can still read and write, without the background jobs.
+    // Dropping the last handle closes the store the same way, without reporting errors
+    pub fn close(self) -> Result<(), Error> {
+        match &self.workers {
+            Some(workers) => workers.shutdown(),
+            None => Ok(()),
+        }
+    }
+
+    // The background merges and syncs run so far
+    #[cfg(test)]
+    pub(crate) fn job_runs(&self) -> (u64, u64) {
+        let runs = self.workers.as_ref().unwrap().runs.0.lock().unwrap();
+        (runs.merges, runs.syncs)
+    }
 
-    for info in keys {
-        file.seek(SeekFrom::Start(info.offset + 8))?;
+    // Waits until the background jobs ran at least `merges` merges and `syncs` syncs in all,
+    // for up to the timeout. Returns whether they did
+    #[cfg(test)]
+    pub(crate) fn wait_for_job_runs(&self, merges: u64, syncs: u64, timeout: Duration) -> bool {
+        let (runs, changed) = &*self.workers.as_ref().unwrap().runs;
+        let runs = runs.lock().unwrap();
+        let (_runs, wait) = changed
+            .wait_timeout_while(runs, timeout, |runs| {
+                runs.merges < merges || runs.syncs < syncs
+            })
+            .unwrap();
+        !wait.timed_out()
+    }
+
+    // Compacts the files past the compaction thresholds right away, instead of waiting for
+    // the background job
+    pub fn merge(&self) -> Result<(), Error> {
+        let thresholds = self.options.compaction_thresholds;
+        self.compact(|stats| {
+            stats.dead_ratio() >= thresholds.dead_ratio || stats.dead_bytes >= thresholds.dead_bytes
+        })
+    }
 
-        let mut length_buf = [0u8; 8];
-        if file.read_exact(&mut length_buf).is_err() {
-            break;
+    // Compacts every file but the active one, dropping tombstones. Rewrites the whole store
+    pub fn merge_all(&self) -> Result<(), Error> {
+        self.compact(|_| true)
+    }
+
+    // A consistent view of the store as it is now, see `Snapshot`
+    pub fn snapshot(&self) -> Snapshot {
+        let _key_dir = self.key_dir.lock().unwrap();
+        let pinned: Vec<u32> = self.file_stats.lock().unwrap().keys().copied().collect();
+        let changes = self.snapshots.lock().unwrap().open(&pinned);
+        // like the merge job, the snapshot does not keep the background jobs running
+        let mut store = self.clone();
+        store.workers = None;
+        Snapshot {
+            store: store.clone(),
+            changes,
+            _pins: Arc::new(Pins {
+                store,
+                file_ids: pinned,
+            }),
+            now: unix_millis(SystemTime::now()),
         }
-        let v_length = usize::from_be_bytes(length_buf);
+    }
 
-        // let mut key_buf = [0u8; 8];
-        // file.read_exact(&mut key_buf)?;
-        // let key = usize::from_be_bytes(key_buf);
-        // do not read key here
-        file.seek(SeekFrom::Current(8))?;
+    // Counters of the value cache, all 0 without one
+    pub fn cache_stats(&self) -> CacheStats {
+        self.value_cache
+            .as_ref()
+            .map(|c| c.stats())
+            .unwrap_or_default()
+    }
 
-        let mut value_buf = vec![0u8; v_length];
-        file.read_exact(&mut value_buf)?;
-        let result =
-            String::from_utf8(value_buf).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
-        results.push((info.name, result));
+    // The open file to read the records of a data file from. Taken with the key dir locked, so
+    // that a compaction cannot delete the file in between
+    fn read_handle(&self, file_id: u32) -> Result<Arc<ReadHandle>, Error> {
+        self.read_handles
+            .get(file_id, || self.files.lock().unwrap().filename(file_id))
     }
 
-    Ok(results)
-}
+    // A reference to the key dir that does not keep it alive, to check that nothing is left
+    // running once the store is closed
+    #[cfg(test)]
+    pub(crate) fn key_dir_weak(&self) -> std::sync::Weak<Mutex<KeyDir>> {
+        Arc::downgrade(&self.key_dir)
+    }
 
-fn read_keys_and_offsets(filename: String) -> Result<Vec<(u64, usize, u64, u64)>, Error> {
-    let mut file = File::open(filename).map_err(|e| Error::new(e.kind(), e.to_string()))?;
-    let mut results = Vec::new();
-    let mut offset = 0;
-    loop {
-        let mut ts_buf = [0u8; 8];
-        if file.read_exact(&mut ts_buf).is_err() {
-            break;
+    #[cfg(test)]
+    pub(crate) fn file_stats(&self, filename: &str) -> FileStats {
+        let id = self.files.lock().unwrap().id(filename);
+        let stats = self.file_stats.lock().unwrap();
+        stats.get(&id).copied().unwrap_or_default()
+    }
+
+    // Same as batch_put, with a sync policy for this batch only. E.g. a bulk load can skip
+    // syncing on a store that syncs every write, or a critical batch can be synced right away
+    pub fn batch_put_with_sync_policy(
+        &mut self,
+        kvs: Vec<KV>,
+        sync_policy: SyncPolicy,
+    ) -> Result<(), Error> {
+        let records = kvs.into_iter().map(|kv| (kv.key, Some(kv.value))).collect();
+        self.write(records, NO_EXPIRY, sync_policy)
+    }
+
+    // Same as put, with the key expiring after `ttl`. Once expired, a key is no longer
+    // returned by `get` or `range`, and compaction drops it
+    pub fn put_with_ttl(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<(), Error> {
+        self.batch_put_with_ttl(vec![KV { key, value }], ttl)
+    }
+
+    pub fn batch_put_with_ttl(&mut self, kvs: Vec<KV>, ttl: Duration) -> Result<(), Error> {
+        self.batch_put_expiring_at(kvs, SystemTime::now() + ttl)
+    }
+
+    // Same as batch_put, with the keys expiring at a given time. Lets a replicated write
+    // expire at the same time on every node, whatever the time it is applied at
+    pub fn batch_put_expiring_at(
+        &mut self,
+        kvs: Vec<KV>,
+        expires_at: SystemTime,
+    ) -> Result<(), Error> {
+        let records = kvs.into_iter().map(|kv| (kv.key, Some(kv.value))).collect();
+        // a time before the epoch would read as no expiry
+        let expires_at = max(unix_millis(expires_at), 1);
+        self.write(records, expires_at, self.options.sync_policy)
+    }
+
+    // Writes the keys one record at a time instead of as a batch, for loads larger than
+    // `max_batch_size`. Files roll over at `max_file_size` as usual, and a crash can leave part
+    // of the keys written
+    pub fn bulk_load(&mut self, kvs: Vec<KV>) -> Result<(), Error> {
+        self.check_writable()?;
+        let records = kvs.into_iter().map(|kv| (kv.key, Some(kv.value))).collect();
+        let mut active_dir = self.active_dir.lock().unwrap();
+        let sync_policy = self.options.sync_policy;
+        self.append(&mut active_dir, records, NO_EXPIRY, sync_policy, false)
+    }
+
+    // Makes the next write take a sequence number, and so a version, of at least `seq`. Nodes
+    // of a cluster write each log entry from a number set by its log index, so versions are
+    // the same on all of them
+    pub(crate) fn skip_seqs_to(&self, seq: u64) {
+        self.next_seq.fetch_max(seq, Ordering::SeqCst);
+    }
+
+    // Fails for more than one record taking more than `max_batch_size` once written as a batch
+    pub(crate) fn check_batch_size<'a>(
+        &self,
+        records: impl ExactSizeIterator<Item = (&'a [u8], Option<&'a [u8]>)>,
+    ) -> Result<(), Error> {
+        if records.len() <= 1 {
+            return Ok(());
+        }
+        let size = batch_size(records);
+        if size > self.options.max_batch_size {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Batch of {} bytes is larger than the maximum of {} bytes",
+                    size, self.options.max_batch_size
+                ),
+            ));
         }
-        let ts = u64::from_be_bytes(ts_buf);
+        Ok(())
+    }
+
+    // Writes the puts and deletes of the batch as a whole, see `WriteBatch`
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<(), Error> {
+        self.write(batch.records, NO_EXPIRY, self.options.sync_policy)
+    }
+
+    // Writes the key if it meets the condition. Writes wait for the active file lock, so the key
+    // cannot change between the check and the write
+    fn write_if(
+        &self,
+        key: Vec<u8>,
+        value: Option<Vec<u8>>,
+        condition: &Condition,
+    ) -> Result<bool, Error> {
+        self.check_writable()?;
+        let mut active_dir = self.active_dir.lock().unwrap();
+        let current = self.get_versioned(&key)?;
+        let met = match (condition, &current) {
+            (Condition::Absent, current) => current.is_none(),
+            (Condition::Version(version), Some((_, current))) => version == current,
+            (Condition::Value(value), Some((current, _))) => value == current,
+            (_, None) => false,
+        };
+        if !met {
+            return Ok(false);
+        }
+        if value.is_some() || current.is_some() {
+            self.append(
+                &mut active_dir,
+                vec![(key, value)],
+                NO_EXPIRY,
+                self.options.sync_policy,
+                true,
+            )?;
+        }
+        Ok(true)
+    }
 
-        let mut length_buf = [0u8; 8];
-        if file.read_exact(&mut length_buf).is_err() {
-            break;
+    // Appends the records to the active file and then updates the key dir.
+    // A `None` value deletes the key. `expires_at` applies to every record of the write.
+    // The records are written as one batch: after a crash, either all of them or none are
+    // found on disk
+    fn write(
+        &self,
+        records: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        expires_at: u64,
+        sync_policy: SyncPolicy,
+    ) -> Result<(), Error> {
+        self.check_writable()?;
+        // holding the active file lock until the key dir is updated keeps compaction from
+        // seeing records that are on disk but not in the key dir yet
+        let mut active_dir = self.active_dir.lock().unwrap();
+        self.append(&mut active_dir, records, expires_at, sync_policy, true)
+    }
+
+    // Without `atomic`, the records are written one by one, as if by separate writes
+    fn append(
+        &self,
+        active_dir: &mut String,
+        records: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        expires_at: u64,
+        sync_policy: SyncPolicy,
+        atomic: bool,
+    ) -> Result<(), Error> {
+        let too_large = records.iter().any(|(_, v)| {
+            v.as_ref()
+                .is_some_and(|v| v.len() >= TOMBSTONE_LENGTH as usize)
+        });
+        if too_large {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Values must be smaller than 4 GB",
+            ));
+        }
+        // a single record needs no framing, it is either whole on disk or a torn tail
+        let atomic = atomic && records.len() > 1;
+        if atomic {
+            self.check_batch_size(records.iter().map(|(k, v)| (k.as_slice(), v.as_deref())))?;
         }
-        let v_length = u64::from_be_bytes(length_buf);
+        let first_seq = self
+            .next_seq
+            .fetch_add(records.len() as u64, Ordering::SeqCst);
+        let records: Vec<SequencedRecord> = records
+            .into_iter()
+            .zip(first_seq..)
+            .map(|((key, value), seq)| (seq, key, value, expires_at))
+            .collect();
+        let (results, new_active_dir) = save(
+            active_dir,
+            &records,
+            atomic,
+            sync_policy,
+            self.options.max_file_size,
+            || self.manifest.lock().unwrap().new_active_file(),
+        )?;
+        *active_dir = new_active_dir;
 
-        let mut key_buf = [0u8; 8];
-        if file.read_exact(&mut key_buf).is_err() {
-            break;
+        let mut kd = self.key_dir.lock().unwrap();
+        let mut stats = self.file_stats.lock().unwrap();
+        let mut files = self.files.lock().unwrap();
+        let mut snapshots = self.snapshots.lock().unwrap();
+        self.read_handles.set_active_file(files.id(active_dir));
+        // the batch markers hold no keys
+        if let (true, Some((dir, _, _, _))) = (atomic, results.first()) {
+            stats.entry(files.id(dir)).or_default().dead_bytes += 2 * HEADER_SIZE;
+        }
+        for ((_, key, value, _), (dir, offset, length, seq)) in records.into_iter().zip(results) {
+            let file_id = files.id(&dir);
+            let new = match value {
+                Some(_) => Key::new(file_id, seq, offset, length as u32),
+                None => Key::new_tombstone(file_id, seq, offset),
+            };
+            stats.entry(file_id).or_default().live_bytes += new.record_size(&key);
+            if let Some(cache) = &self.value_cache {
+                cache.remove(&key);
+            }
+            let old = kd.insert(key.clone(), new)?;
+            snapshots.preserve(&key, old);
+            if let Some(old) = old {
+                let size = old.record_size(&key);
+                let old_stats = stats.entry(old.file_id).or_default();
+                old_stats.live_bytes -= size;
+                old_stats.dead_bytes += size;
+            }
         }
-        let key = usize::from_be_bytes(key_buf);
-        results.push((ts, key, offset, v_length));
 
-        if file.seek(SeekFrom::Current(v_length as i64)).is_err() {
-            break;
+        Ok(())
+    }
+
+    // Sealed files get their hint file from the compaction job, or on the next startup
+    fn write_missing_hint_files(&self) -> Result<(), Error> {
+        let files: Vec<String> = {
+            let active_dir = self.active_dir.lock().unwrap();
+            let manifest = self.manifest.lock().unwrap();
+            manifest
+                .data_files()
+                .into_iter()
+                .filter(|f| *f != *active_dir && !has_hint_file(f))
+                .collect()
         };
-        offset += 8 + 8 + 8 + v_length; // Move to the next key
+        for filename in files {
+            let (records, _) = read_keys_and_offsets(filename.clone())?;
+            write_hint_file(&filename, &records, false)?;
+        }
+        Ok(())
     }
 
-    Ok(results)
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.options.read_only {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "Storage is opened as read only",
+            ));
+        }
+        Ok(())
+    }
+
+    // Rewrites the live records of the files selected by their stats into new files, and
+    // deletes them. Tombstones are only dropped when every file but the active one is merged,
+    // since otherwise an older value of the key could be left in a file that is kept
+    fn compact(&self, select: impl Fn(&FileStats) -> bool) -> Result<(), Error> {
+        self.check_writable()?;
+        let _merging = self.merging.lock().unwrap();
+        self.write_missing_hint_files()?;
+        println!("compaction starting...");
+        let (merged_files, drop_tombstones) = {
+            let active_dir = self.active_dir.lock().unwrap();
+            let stats = self.file_stats.lock().unwrap();
+            let mut files = self.files.lock().unwrap();
+            let file_ids: Vec<u32> = self
+                .manifest
+                .lock()
+                .unwrap()
+                .data_files()
+                .into_iter()
+                .filter(|f| *f != *active_dir)
+                .map(|f| files.id(&f))
+                .collect();
+            let file_count = file_ids.len();
+            let merged_files: HashSet<u32> = file_ids
+                .into_iter()
+                .filter(|id| select(&stats.get(id).copied().unwrap_or_default()))
+                .collect();
+            let drop_tombstones = merged_files.len() == file_count;
+            (merged_files, drop_tombstones)
+        };
+        if merged_files.is_empty() {
+            println!("No files to compact");
+            return Ok(());
+        }
+
+        // the keys are copied and installed a chunk at a time, so memory use does not grow
+        // with the key set, and other processes only wait for the key dir while a chunk is
+        // installed
+        let mut output = MergeOutput::new(
+            drop_tombstones,
+            self.options.max_file_size,
+            &self.files,
+            &self.read_handles,
+            &self.manifest,
+        )?;
+        let mut start = Unbounded;
+        loop {
+            let (keys, scanned_to) = self.merged_keys(&merged_files, start.as_ref())?;
+            let relocated = output.copy(keys)?;
+            self.install_relocations(relocated)?;
+            match scanned_to {
+                Some(last) => start = Excluded(last),
+                None => break,
+            }
+        }
+        output.finish()?;
+        println!("Key dir updated! Deleting old files...");
+        // the lock is held so that no read opens a file while it is deleted
+        let _key_dir = self.key_dir.lock().unwrap();
+        let mut stats = self.file_stats.lock().unwrap();
+        delete_old_files(
+            merged_files,
+            &mut stats,
+            &mut self.files.lock().unwrap(),
+            &self.read_handles,
+            &mut self.manifest.lock().unwrap(),
+            &mut self.snapshots.lock().unwrap(),
+            self.next_seq.load(Ordering::SeqCst),
+        )?;
+        Ok(())
+    }
+
+    // Up to COMPACT_CHUNK_KEYS keys of the merged files, in order from `start`, and the last key
+    // scanned when the scan stopped before the end. A scan goes over at most COMPACT_SCAN_KEYS
+    // keys, so the key dir is not held for long when few of them are in the merged files
+    fn merged_keys(
+        &self,
+        merged_files: &HashSet<u32>,
+        start: Bound<&Vec<u8>>,
+    ) -> Result<(KeyLocations, Option<Vec<u8>>), Error> {
+        let mut key_dir = self.key_dir.lock().unwrap();
+        let mut keys = Vec::new();
+        let mut scanned = 0;
+        let mut scanned_to = None;
+        key_dir.scan(start.map(Vec::as_slice), Unbounded, false, |k, v| {
+            if merged_files.contains(&v.file_id) {
+                keys.push((k.to_vec(), *v));
+            }
+            scanned += 1;
+            if keys.len() == COMPACT_CHUNK_KEYS || scanned == COMPACT_SCAN_KEYS {
+                scanned_to = Some(k.to_vec());
+                return false;
+            }
+            true
+        })?;
+        Ok((keys, scanned_to))
+    }
+
+    // Points the keys to their copies, and updates the stats of the files. Keys written or
+    // deleted while they were copied are left as they are
+    fn install_relocations(&self, relocated: Vec<(Vec<u8>, Relocation)>) -> Result<(), Error> {
+        let mut key_dir = self.key_dir.lock().unwrap();
+        let mut stats = self.file_stats.lock().unwrap();
+        for (k, (old, new)) in relocated {
+            // keys written or deleted while merging already point past the copied record
+            let installed = key_dir
+                .get(&k)?
+                .is_some_and(|live| live.same_location(&old));
+            if installed {
+                let size = old.record_size(&k);
+                let old_stats = stats.entry(old.file_id).or_default();
+                old_stats.live_bytes -= size;
+                old_stats.dead_bytes += size;
+            }
+            if let Some(new) = new {
+                let new_stats = stats.entry(new.file_id).or_default();
+                if installed {
+                    new_stats.live_bytes += new.record_size(&k);
+                    self.snapshots.lock().unwrap().preserve(&k, Some(old));
+                    key_dir.insert(k, new)?;
+                } else {
+                    new_stats.dead_bytes += new.record_size(&k);
+                }
+            } else if installed {
+                self.snapshots.lock().unwrap().preserve(&k, Some(old));
+                key_dir.remove(&k)?;
+            }
+        }
+        Ok(())
+    }
 }
 
-fn compute_key_dir(data_dir: &str, active_file: &str) -> Result<BTreeMap<usize, Key>, Error> {
-    let r = read_hint_file(data_dir);
-    if r.is_ok() {
-        return r;
+// Keys of the merged files a compaction copies and installs at a time, and the most keys it
+// scans for them at once
+const COMPACT_CHUNK_KEYS: usize = 10_000;
+const COMPACT_SCAN_KEYS: usize = 100_000;
+
+// Keys read at a time by a range iterator, and about the most bytes of values it reads at once.
+// A single value larger than that is still read whole
+const ITER_BATCH_KEYS: usize = 256;
+const ITER_BATCH_BYTES: usize = 4 << 20;
+
+// Bounds of the keys a scan has yet to read
+type ScanBounds = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+// A consistent view of the store as it was when the snapshot was taken. Its reads and
+// iterators do not see later writes, and expiries are checked against the time it was taken.
+// The data files it may read are kept, even by a compaction that is done with them, until
+// the snapshot and its clones are dropped
+#[derive(Clone)]
+pub struct Snapshot {
+    store: BitCask,
+    changes: Arc<Changes>,
+    // released with the last clone
+    _pins: Arc<Pins>,
+    now: u64,
+}
+
+// The files pinned by a snapshot
+struct Pins {
+    store: BitCask,
+    file_ids: Vec<u32>,
+}
+
+impl Snapshot {
+    pub fn get(&self, key: &[u8]) -> Result<Vec<u8>, Error> {
+        let k = {
+            let mut kd = self.store.key_dir.lock().unwrap();
+            let changed = self.changes.lock().unwrap().get(key).copied();
+            match changed {
+                Some(k) => k,
+                None => kd.get(key)?,
+            }
+        };
+        let Some(k) = k.filter(|k| !k.is_tombstone()) else {
+            return Ok(vec![]);
+        };
+        let file = self.store.read_handle(k.file_id)?;
+        let (kv, expires_at) = read_from_file(&file, vec![(key.to_vec(), k)])?
+            .pop()
+            .unwrap();
+        if is_expired(expires_at, self.now) {
+            return Ok(vec![]);
+        }
+        Ok(kv.value)
     }
 
-    println!("No hint file present. Build key dir from data files...");
-    let mut new_dir: BTreeMap<usize, Key> = BTreeMap::new();
+    pub fn range(&self, start: &[u8], end: &[u8]) -> Result<Vec<KV>, Error> {
+        let query = RangeQuery::new(Included(start.to_vec()), Included(end.to_vec()));
+        Ok(self.scan(&query)?.kvs)
+    }
+
+    // Reads the whole page at once. Use `iter` to stream a range without holding all of it
+    pub fn scan(&self, query: &RangeQuery) -> Result<RangePage, Error> {
+        let mut bounds = query.bounds();
+        let limit = query.limit.unwrap_or(usize::MAX);
+        let mut kvs = Vec::new();
+        while kvs.len() < limit {
+            let (batch, done) = self.read_next(&mut bounds, limit - kvs.len(), query.reverse)?;
+            kvs.extend(batch);
+            if done {
+                return Ok(RangePage { kvs, cursor: None });
+            }
+        }
+        let cursor = kvs.last().map(|kv| kv.key.clone());
+        Ok(RangePage { kvs, cursor })
+    }
+
+    // The keys of the query, in its order. Values are read a batch at a time, from several
+    // files at once on the threads of the store, and the next batch is read while the last one
+    // is consumed. Memory use does not grow with the range and an iterator starts no thread of
+    // its own. The snapshot is kept until the iterator is dropped
+    pub fn iter(&self, query: &RangeQuery) -> RangeIter {
+        RangeIter {
+            snapshot: self.clone(),
+            bounds: query.bounds(),
+            remaining: query.limit.unwrap_or(usize::MAX),
+            reverse: query.reverse,
+            done: false,
+            current: Vec::new().into_iter(),
+            next: None,
+        }
+    }
+
+    // Reads the next keys within the bounds, up to `limit` of them and about ITER_BATCH_BYTES
+    // of values, and narrows the bounds past them. Returns the values in the order of the
+    // scan, and whether the bounds hold no more keys. Expired keys are left out, so a batch
+    // may come back short of the limit before the end
+    fn read_next(
+        &self,
+        bounds: &mut ScanBounds,
+        limit: usize,
+        reverse: bool,
+    ) -> Result<(Vec<KV>, bool), Error> {
+        let (batch, done) = self.start_next(bounds, limit, reverse)?;
+        Ok((batch.wait()?, done))
+    }
 
-    for entry in fs::read_dir(data_dir)? {
-        let path = entry?.path();
-        let Some(file) = path.file_name() else {
-            return Err(Error::new(ErrorKind::InvalidData, "Path is not a file"));
+    // Same as `read_next`, without waiting for the values to be read
+    fn start_next(
+        &self,
+        bounds: &mut ScanBounds,
+        limit: usize,
+        reverse: bool,
+    ) -> Result<(PendingBatch, bool), Error> {
+        let (start, end) = bounds;
+        let mut keys = self.keys(
+            start.as_ref().map(Vec::as_slice),
+            end.as_ref().map(Vec::as_slice),
+            limit,
+            reverse,
+        )?;
+        let mut done = keys.len() < limit;
+        let mut bytes = 0;
+        let over_budget = keys.iter().position(|(key, k)| {
+            bytes += key.len() + k.length();
+            bytes > ITER_BATCH_BYTES
+        });
+        if let Some(over_budget) = over_budget {
+            let kept = max(over_budget, 1);
+            done &= kept == keys.len();
+            keys.truncate(kept);
+        }
+        match keys.last() {
+            Some((last, _)) if !done && reverse => *end = Excluded(last.clone()),
+            Some((last, _)) if !done => *start = Excluded(last.clone()),
+            _ => done = true,
+        }
+
+        let batch = PendingBatch {
+            read: self.start_read(keys)?,
+            reverse,
         };
-        let Some(filename) = file.to_str() else {
-            return Err(Error::new(
-                ErrorKind::InvalidData,
-                "File does not have a name",
-            ));
+        Ok((batch, done))
+    }
+
+    // Starts reading the keys grouped by file, in no particular order
+    fn start_read(&self, keys: Vec<(Vec<u8>, Key)>) -> Result<PendingRead, Error> {
+        let mut grouped_ids: HashMap<u32, Vec<(Vec<u8>, Key)>> = HashMap::new();
+        for (key, k) in keys {
+            grouped_ids.entry(k.file_id).or_default().push((key, k));
+        }
+        let grouped_keys = grouped_ids
+            .into_iter()
+            .map(|(id, keys)| Ok((self.store.read_handle(id)?, keys)))
+            .collect::<Result<_, Error>>()?;
+        Ok(start_read(&self.store.io_pool, grouped_keys, self.now))
+    }
+
+    // Up to `limit` keys of the snapshot in the range, in order or in reverse order, without
+    // tombstones
+    fn keys(
+        &self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        limit: usize,
+        reverse: bool,
+    ) -> Result<Vec<(Vec<u8>, Key)>, Error> {
+        if is_empty_range(start, end) {
+            return Ok(vec![]);
+        }
+        let mut kd = self.store.key_dir.lock().unwrap();
+        let changes = self.changes.lock().unwrap();
+        let mut keys: BTreeMap<Vec<u8>, Key> = BTreeMap::new();
+        let mut unchanged = 0;
+        kd.scan(start, end, reverse, |key, k| {
+            if !changes.contains_key(key) && !k.is_tombstone() {
+                keys.insert(key.to_vec(), *k);
+                unchanged += 1;
+            }
+            unchanged < limit
+        })?;
+        let changed = changes
+            .range::<[u8], _>((start, end))
+            .filter_map(|(key, k)| k.filter(|k| !k.is_tombstone()).map(|k| (key, k)));
+        let changed: Box<dyn Iterator<Item = _>> = if reverse {
+            Box::new(changed.rev())
+        } else {
+            Box::new(changed)
         };
-        let full_filename = format!("{}/{}", data_dir, filename);
-        if full_filename.ends_with(HINT_FILE_NAME) || full_filename == active_file {
-            continue;
+        for (key, k) in changed.take(limit) {
+            keys.insert(key.to_vec(), k);
         }
-        // ignoring corrupted files might be preferable to failing the entire merge
-        let k = read_keys_and_offsets(full_filename.to_string())?;
-        for (ts, key, offset, v_len) in k {
-            if new_dir.contains_key(&key) {
-                let existing = new_dir.get(&key);
-                if existing.unwrap().timestamp >= ts {
-                    continue;
-                }
+        Ok(match reverse {
+            false => keys.into_iter().take(limit).collect(),
+            true => keys.into_iter().rev().take(limit).collect(),
+        })
+    }
+}
+
+impl Drop for Pins {
+    fn drop(&mut self) {
+        let unpinned = self.store.snapshots.lock().unwrap().close(&self.file_ids);
+        for file_id in unpinned {
+            let filename = self.store.files.lock().unwrap().filename(file_id);
+            let deleted =
+                filename.and_then(|f| delete_data_file(&f, file_id, &self.store.read_handles));
+            match deleted {
+                Ok(()) => self.store.files.lock().unwrap().remove(file_id),
+                Err(e) => println!("Could not delete merged file {}: {}", file_id, e),
             }
-            new_dir.insert(
-                key,
-                Key {
-                    filename: full_filename.to_string(),
-                    timestamp: ts,
-                    name: key,
-                    offset: offset,
-                    length: v_len as usize,
-                },
-            );
         }
     }
-    Ok(new_dir)
-}
-
-fn compact_files(
-    data_dir: &str,
-    key_dir: BTreeMap<usize, Key>,
-) -> Result<BTreeMap<usize, Key>, Error> {
-    let mut active_dir = create_new_active_file(&data_dir)?;
-    let mut new_dir: BTreeMap<usize, Key> = BTreeMap::new();
-
-    for (k, v) in key_dir {
-        // Could probably write multiple keys, to avoid opening the file multiple times
-        let result = read_from_file(v.filename.clone(), vec![v])?;
-        let (new_key, filename) = save(data_dir, &*active_dir, result)?;
-        active_dir = filename;
-        let (dir, offset, length, ts) = new_key.first().unwrap();
-        new_dir.insert(
-            k,
-            Key {
-                filename: dir.clone(),
-                timestamp: *ts,
-                name: k,
-                offset: *offset,
-                length: *length,
-            },
-        );
+}
+
+// A batch of a range read, see `Snapshot::start_next`
+struct PendingBatch {
+    read: PendingRead,
+    reverse: bool,
+}
+
+impl PendingBatch {
+    // Waits for the values, in the order of the scan
+    fn wait(self) -> Result<Vec<KV>, Error> {
+        let mut kvs = self.read.wait()?;
+        kvs.sort_by(|a, b| a.key.cmp(&b.key));
+        if self.reverse {
+            kvs.reverse();
+        }
+        Ok(kvs)
     }
+}
 
-    Ok(new_dir)
+// Streams the keys of a range query, see `Snapshot::iter`. Stays valid while the store is
+// written to and compacted
+pub struct RangeIter {
+    snapshot: Snapshot,
+    bounds: ScanBounds,
+    remaining: usize,
+    reverse: bool,
+    done: bool,
+    current: std::vec::IntoIter<KV>,
+    // the batch after `current`, read while `current` is consumed
+    next: Option<Result<PendingBatch, Error>>,
 }
 
-fn create_hint_file(data_dir: &str, key_dir: BTreeMap<usize, Key>) -> Result<(), Error> {
-    let filename = format!("{}/{}", data_dir, HINT_FILE_NAME);
-    create_new_file(&filename)?;
+impl RangeIter {
+    // Starts reading the next batch, if the range has one
+    fn prefetch(&mut self) {
+        if self.done || self.remaining == 0 {
+            return;
+        }
+        let limit = min(self.remaining, ITER_BATCH_KEYS);
+        let next = self
+            .snapshot
+            .start_next(&mut self.bounds, limit, self.reverse);
+        match &next {
+            Ok((_, done)) => self.done = *done,
+            // the iteration ends at the first error
+            Err(_) => self.done = true,
+        }
+        self.next = Some(next.map(|(batch, _)| batch));
+    }
+
+    // Whether the batch after the current one is already being read
+    #[cfg(test)]
+    pub(crate) fn prefetching(&self) -> bool {
+        matches!(self.next, Some(Ok(_)))
+    }
+}
 
-    let mut file = OpenOptions::new().write(true).append(true).open(filename)?;
+impl Iterator for RangeIter {
+    type Item = Result<KV, Error>;
 
-    for (_, v) in key_dir {
-        file.write_all(&v.timestamp.to_be_bytes())?;
-        file.write_all(&v.length.to_be_bytes())?;
-        file.write_all(&v.name.to_be_bytes())?;
-        file.write_all(&v.filename.len().to_be_bytes())?;
-        file.write_all(v.filename.as_bytes())?;
-        file.write_all(&v.offset.to_be_bytes())?;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(kv) = self.current.next() {
+                return Some(Ok(kv));
+            }
+            if self.next.is_none() {
+                self.prefetch();
+            }
+            match self.next.take()?.and_then(PendingBatch::wait) {
+                Ok(batch) => {
+                    self.remaining -= batch.len();
+                    self.current = batch.into_iter();
+                    // read on the pool while this batch is consumed
+                    self.prefetch();
+                }
+                Err(e) => {
+                    self.done = true;
+                    self.next = None;
+                    return Some(Err(e));
+                }
+            }
+        }
     }
+}
 
-    Ok(())
+// Whether no key can be in the range. Such bounds are not valid for a BTreeMap range
+fn is_empty_range(start: Bound<&[u8]>, end: Bound<&[u8]>) -> bool {
+    match (start, end) {
+        (Included(s), Included(e)) => s > e,
+        (Included(s) | Excluded(s), Included(e) | Excluded(e)) => s >= e,
+        _ => false,
+    }
 }
 
-fn read_hint_file(data_dir: &str) -> Result<BTreeMap<usize, Key>, Error> {
-    let filename = format!("{}/{}", data_dir, HINT_FILE_NAME);
-    let mut file = OpenOptions::new().read(true).open(filename)?;
+// Reads at the offsets of the keys, so the same file can be read by several threads at once.
+// Returns each value with the expiry of its record, whether it expired or not
+fn read_from_file(file: &ReadHandle, keys: Vec<(Vec<u8>, Key)>) -> Result<Vec<(KV, u64)>, Error> {
+    let mut results = Vec::new();
+
+    for (key, info) in keys {
+        let mut header = vec![0u8; HEADER_SIZE as usize + key.len() + info.length()];
+        file.read_at(&mut header, info.offset)?;
+        let mut body = header.split_off(HEADER_SIZE as usize);
+        check_record(&header, &body)?;
+        let (_, _, expires_at, _, _) = decode_header(&header);
+
+        let value = body.split_off(key.len());
+        results.push((KV { key, value }, expires_at));
+    }
+
+    Ok(results)
+}
 
-    println!("Hint file found. Loading key dir from it...");
-    let mut new_dir: BTreeMap<usize, Key> = BTreeMap::new();
-    loop {
-        let mut ts_buf = [0u8; 8];
-        if file.read_exact(&mut ts_buf).is_err() {
-            break;
+// Returns the valid records of the file, and the offset where a torn or unreadable tail
+// starts, if there is one. Corrupt records are reported and skipped, along with the rest of
+// their batch. The records of a batch are only returned once its commit marker is read, so
+// a batch that was cut short by a crash is part of the tail
+fn read_keys_and_offsets(filename: String) -> Result<(Vec<RecordInfo>, Option<u64>), Error> {
+    let file = File::open(&filename).map_err(|e| Error::new(e.kind(), e.to_string()))?;
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut results = Vec::new();
+    let mut batch: Option<OpenBatch> = None;
+    let mut offset = 0;
+    while offset < file_len {
+        let torn_offset = batch.as_ref().map_or(offset, |b| b.offset);
+        if offset + HEADER_SIZE > file_len {
+            println!("Torn record at the end of {} (offset {})", filename, offset);
+            return Ok((results, Some(torn_offset)));
         }
-        let ts = u64::from_be_bytes(ts_buf);
+        let mut header = [0u8; HEADER_SIZE as usize];
+        reader.read_exact(&mut header)?;
+        let (_, seq, expires_at, length, k_length) = decode_header(&header);
+        let tombstone = length == TOMBSTONE;
+        let marker = length == BATCH_BEGIN || length == BATCH_COMMIT;
+        let v_length = if tombstone || marker { 0 } else { length };
 
-        let mut length_buf = [0u8; 8];
-        if file.read_exact(&mut length_buf).is_err() {
-            break;
+        // a record running past the end of the file was either partially written, or has a
+        // corrupt length. Only the first is at the tail, with no valid record after it
+        let remaining = file_len - offset - HEADER_SIZE;
+        let fits = k_length <= remaining && v_length <= remaining - k_length;
+        let mut body = vec![
+            0u8;
+            if fits {
+                (k_length + v_length) as usize
+            } else {
+                0
+            }
+        ];
+        if fits {
+            reader.read_exact(&mut body)?;
+        }
+        let record_offset = offset;
+        if !fits || check_record(&header, &body).is_err() {
+            // the length may be the corrupt part, so the next record is looked for byte by byte
+            let Some(next) = find_next_record(&mut reader, record_offset + 1, file_len)? else {
+                println!("Torn record at the end of {} (offset {})", filename, offset);
+                return Ok((results, Some(torn_offset)));
+            };
+            println!(
+                "Skipping {} corrupt bytes in {} (offset {})",
+                next - record_offset,
+                filename,
+                record_offset
+            );
+            if let Some(batch) = &mut batch {
+                batch.corrupt = true;
+            }
+            offset = next;
+            continue;
         }
-        let v_length = u64::from_be_bytes(length_buf);
+        offset += HEADER_SIZE + k_length + v_length; // Move to the next key
 
-        let mut key_buf = [0u8; 8];
-        if file.read_exact(&mut key_buf).is_err() {
-            break;
+        // the expiry of a marker holds the number of records of the batch
+        match (length, batch.take()) {
+            (BATCH_BEGIN, open) => {
+                if let Some(open) = open {
+                    println!(
+                        "Skipping uncommitted batch in {} (offset {})",
+                        filename, open.offset
+                    );
+                }
+                batch = Some(OpenBatch {
+                    offset: record_offset,
+                    seq,
+                    count: expires_at,
+                    records: Vec::new(),
+                    corrupt: false,
+                });
+            }
+            (BATCH_COMMIT, Some(mut open))
+                if open.seq == seq && open.count == expires_at && !open.corrupt =>
+            {
+                results.append(&mut open.records);
+            }
+            (BATCH_COMMIT, open) => {
+                let open_offset = open.map_or(record_offset, |b| b.offset);
+                println!(
+                    "Skipping incomplete batch in {} (offset {})",
+                    filename, open_offset
+                );
+            }
+            (_, open) => {
+                body.truncate(k_length as usize);
+                let record = (seq, body, record_offset, v_length, tombstone);
+                match open {
+                    Some(mut open) => {
+                        open.records.push(record);
+                        batch = Some(open);
+                    }
+                    None => results.push(record),
+                }
+            }
         }
-        let key = usize::from_be_bytes(key_buf);
+    }
 
-        let mut filename_len_buf = [0u8; 8];
-        if file.read_exact(&mut filename_len_buf).is_err() {
-            break;
+    match batch {
+        Some(open) => {
+            println!(
+                "Uncommitted batch at the end of {} (offset {})",
+                filename, open.offset
+            );
+            Ok((results, Some(open.offset)))
+        }
+        None => Ok((results, None)),
+    }
+}
+
+// The offset of the first valid record at or after `from`, with the reader moved to it.
+// `None` if there is none up to the end of the file
+fn find_next_record(
+    reader: &mut BufReader<File>,
+    from: u64,
+    file_len: u64,
+) -> Result<Option<u64>, Error> {
+    let mut header = [0u8; HEADER_SIZE as usize];
+    reader.seek(SeekFrom::Start(from))?;
+    for offset in from..file_len.saturating_sub(HEADER_SIZE - 1) {
+        reader.read_exact(&mut header)?;
+        let (_, _, _, length, k_length) = decode_header(&header);
+        let v_length = match length {
+            TOMBSTONE | BATCH_BEGIN | BATCH_COMMIT => 0,
+            _ => length,
+        };
+        let remaining = file_len - offset - HEADER_SIZE;
+        let mut read = HEADER_SIZE as i64;
+        if k_length <= remaining && v_length <= remaining - k_length {
+            let mut body = vec![0u8; (k_length + v_length) as usize];
+            reader.read_exact(&mut body)?;
+            if check_record(&header, &body).is_ok() {
+                reader.seek(SeekFrom::Start(offset))?;
+                return Ok(Some(offset));
+            }
+            read += body.len() as i64;
         }
-        let filename_len = usize::from_be_bytes(filename_len_buf);
+        // back to the byte after this offset, which the buffer most likely still holds
+        reader.seek_relative(1 - read)?;
+    }
+    Ok(None)
+}
+
+// A batch whose begin marker was read, and not its commit marker yet
+struct OpenBatch {
+    offset: u64,
+    seq: u64,
+    count: u64,
+    records: Vec<RecordInfo>,
+    corrupt: bool,
+}
+
+// Reads each file from its hint file, or scans the ones without a valid hint. With `repair`,
+// a torn tail is truncated and the missing hint files are written, otherwise the data dir is
+// left as it is. `last_file` is the one being written when the store last stopped.
+// Returns the sequence number that follows the highest one on disk
+fn compute_key_dir(
+    files: Vec<String>,
+    last_file: Option<String>,
+    repair: bool,
+    new_dir: &mut KeyDir,
+    file_table: &mut FileTable,
+) -> Result<u64, Error> {
+    // a record copied by a compaction keeps the sequence number of the original. Both are
+    // only on disk if the compaction stopped before deleting the merged files, and then the
+    // copy is kept, so the files written by compactions are read first
+    let (mut ordered, rest): (Vec<String>, Vec<String>) =
+        files.into_iter().partition(|f| is_compacted(f));
+    ordered.extend(rest);
+    let mut next_seq = 0;
 
-        let mut filename_buf = vec![0u8; filename_len];
-        if file.read_exact(&mut filename_buf).is_err() {
-            break;
+    // tombstones are kept in the key dir, so that older values cannot bring a key back
+    for full_filename in ordered {
+        let k = match read_hint_file(&full_filename) {
+            Ok(k) => k,
+            Err(e) => {
+                if e.kind() != ErrorKind::NotFound {
+                    println!("Ignoring hint of {}: {}", full_filename, e);
+                }
+                let (k, torn_offset) = read_keys_and_offsets(full_filename.to_string())?;
+                if let Some(torn_offset) = torn_offset {
+                    if repair && Some(&full_filename) == last_file.as_ref() {
+                        println!("Truncating {} to {} bytes", full_filename, torn_offset);
+                        truncate_file(&full_filename, torn_offset)?;
+                    }
+                }
+                if repair {
+                    write_hint_file(&full_filename, &k, false)?;
+                }
+                k
+            }
+        };
+        let file_id = file_table.id(&full_filename);
+        for (seq, key, offset, v_len, tombstone) in k {
+            next_seq = max(next_seq, seq + 1);
+            if new_dir.get(&key)?.is_some_and(|k| k.seq >= seq) {
+                continue;
+            }
+            let key_info = if tombstone {
+                Key::new_tombstone(file_id, seq, offset)
+            } else {
+                Key::new(file_id, seq, offset, v_len as u32)
+            };
+            new_dir.insert(key, key_info)?;
         }
-        let filename =
-            String::from_utf8(filename_buf).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    }
+    Ok(next_seq)
+}
+
+// The live bytes of each file are the records the key dir points to, the rest is dead
+fn compute_file_stats(
+    data_files: &[String],
+    key_dir: &mut KeyDir,
+    files: &mut FileTable,
+) -> Result<HashMap<u32, FileStats>, Error> {
+    let mut stats: HashMap<u32, FileStats> = HashMap::new();
+    key_dir.scan(Unbounded, Unbounded, false, |k, v| {
+        stats.entry(v.file_id).or_default().live_bytes += v.record_size(k);
+        true
+    })?;
+    for full_filename in data_files {
+        let file_len = fs::metadata(full_filename)?.len();
+        let file_stats = stats.entry(files.id(full_filename)).or_default();
+        file_stats.dead_bytes = file_len.saturating_sub(file_stats.live_bytes);
+    }
+    Ok(stats)
+}
 
-        let mut offset_buf = [0u8; 8];
-        if file.read_exact(&mut offset_buf).is_err() {
-            break;
+// Location a key was copied from, and where it was copied to. `None` for a dropped tombstone
+type Relocation = (Key, Option<Key>);
+
+// Keys and where their records are
+type KeyLocations = Vec<(Vec<u8>, Key)>;
+
+// The files a compaction copies records to. Each one is synced and gets its hint file once
+// it is full, so only the records of the file being written are kept in memory
+struct MergeOutput<'a> {
+    drop_tombstones: bool,
+    max_file_size: u64,
+    files: &'a Mutex<FileTable>,
+    read_handles: &'a ReadHandles,
+    manifest: &'a Mutex<Manifest>,
+    active_dir: String,
+    records: Vec<RecordInfo>,
+    now: u64,
+}
+
+impl<'a> MergeOutput<'a> {
+    fn new(
+        drop_tombstones: bool,
+        max_file_size: u64,
+        files: &'a Mutex<FileTable>,
+        read_handles: &'a ReadHandles,
+        manifest: &'a Mutex<Manifest>,
+    ) -> Result<Self, Error> {
+        let active_dir = manifest.lock().unwrap().new_merge_output()?;
+        Ok(MergeOutput {
+            drop_tombstones,
+            max_file_size,
+            files,
+            read_handles,
+            manifest,
+            active_dir,
+            records: Vec::new(),
+            now: unix_millis(SystemTime::now()),
+        })
+    }
+
+    // Copies the records of the keys, and returns where each one went. None for a key
+    // dropped, a key whose record could not be read is left out
+    fn copy(&mut self, keys: KeyLocations) -> Result<Vec<(Vec<u8>, Relocation)>, Error> {
+        let files = self.files;
+        let manifest = self.manifest;
+        let new_file = || manifest.lock().unwrap().new_merge_output();
+        let mut relocated = Vec::with_capacity(keys.len());
+        for (k, v) in keys {
+            if v.is_tombstone() && self.drop_tombstones {
+                relocated.push((k, (v, None)));
+                continue;
+            }
+            let (value, expires_at) = if v.is_tombstone() {
+                (None, NO_EXPIRY)
+            } else {
+                let file = self
+                    .read_handles
+                    .get(v.file_id, || files.lock().unwrap().filename(v.file_id))?;
+                match read_from_file(&file, vec![(k.clone(), v)]) {
+                    Ok(mut result) => {
+                        let (kv, expires_at) = result.pop().unwrap();
+                        (Some(kv.value), expires_at)
+                    }
+                    // leave the key where it is, its file is kept since the key still points
+                    // to it
+                    Err(e) if e.kind() == ErrorKind::InvalidData => {
+                        println!("Could not compact key {:?}: {}", k, e);
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                }
+            };
+            // an expired key is dropped like a deleted one. Older values of the key may remain
+            // in the files left out of the compaction, so it is kept as a tombstone until they
+            // are gone
+            let expired = is_expired(expires_at, self.now);
+            if expired && self.drop_tombstones {
+                relocated.push((k, (v, None)));
+                continue;
+            }
+            let (value, expires_at) = if expired {
+                (None, NO_EXPIRY)
+            } else {
+                (value, expires_at)
+            };
+            let tombstone = value.is_none();
+            let records: Vec<SequencedRecord> = vec![(v.seq, k.clone(), value, expires_at)];
+            let (new_key, filename) = save(
+                &self.active_dir,
+                &records,
+                false,
+                SyncPolicy::OsManaged,
+                self.max_file_size,
+                new_file,
+            )?;
+            let (dir, offset, length, seq) = new_key.first().unwrap();
+            let file_id = files.lock().unwrap().id(dir);
+            let new = if tombstone {
+                Key::new_tombstone(file_id, *seq, *offset)
+            } else {
+                Key::new(file_id, *seq, *offset, *length as u32)
+            };
+            self.records
+                .push((*seq, k.clone(), *offset, *length as u64, tombstone));
+            relocated.push((k, (v, Some(new))));
+            // the record went to the file before it rolled over
+            if filename != self.active_dir {
+                self.seal()?;
+                self.active_dir = filename;
+            }
         }
-        let offset = usize::from_be_bytes(offset_buf);
+        Ok(relocated)
+    }
 
-        new_dir.insert(
-            key,
-            Key {
-                filename,
-                timestamp: ts,
-                name: key,
-                offset: offset as u64,
-                length: v_length as usize,
-            },
-        );
+    // The merged files are deleted afterwards, so the copies must be on disk first
+    fn seal(&mut self) -> Result<(), Error> {
+        sync_file(&self.active_dir)?;
+        let records = std::mem::take(&mut self.records);
+        write_hint_file(&self.active_dir, &records, true)
     }
 
-    Ok(new_dir)
+    fn finish(mut self) -> Result<(), Error> {
+        self.seal()
+    }
 }
 
+// Installs the outputs of the compaction in the manifest, and deletes the merged files that
+// no key points to anymore, their stats and ids. Files that a snapshot may still read are
+// deleted once it is dropped. A file that is out of the manifest but not deleted yet is
+// removed on the next startup. `next_seq` goes to the manifest, since the records with the
+// highest sequence numbers may be among the dropped tombstones
 fn delete_old_files(
-    data_dir: &str,
-    active_dir: &str,
-    key_dir: Arc<Mutex<BTreeMap<usize, Key>>>,
+    merged_files: HashSet<u32>,
+    stats: &mut HashMap<u32, FileStats>,
+    files: &mut FileTable,
+    read_handles: &ReadHandles,
+    manifest: &mut Manifest,
+    snapshots: &mut Snapshots,
+    next_seq: u64,
 ) -> Result<(), Error> {
-    let mut used_files: HashSet<String> = HashSet::new();
-
-    // ensure no new active files are created while we read the list of files
-    let l = key_dir.lock();
-    let files = fs::read_dir(data_dir)?;
-    for v in l.unwrap().values() {
-        used_files.insert(v.filename.to_string());
+    let mut deleted = Vec::new();
+    for file_id in merged_files {
+        if stats.get(&file_id).is_some_and(|s| s.live_bytes > 0) {
+            continue;
+        }
+        deleted.push((file_id, files.filename(file_id)?));
     }
-
-    let mut count = 0;
-    for entry in files {
-        let path = entry?.path();
-        let Some(file) = path.file_name() else {
-            return Err(Error::new(ErrorKind::InvalidData, "Path is not a file"));
-        };
-        let Some(filename) = file.to_str() else {
-            return Err(Error::new(
-                ErrorKind::InvalidData,
-                "File does not have a name",
-            ));
-        };
-        let full_filename = format!("{}/{}", data_dir, filename);
-        if full_filename.ends_with(HINT_FILE_NAME)
-            || full_filename == active_dir
-            || used_files.contains(&full_filename.to_string())
-        {
+    let filenames: Vec<String> = deleted.iter().map(|(_, f)| f.clone()).collect();
+    manifest.install_merge(&filenames, next_seq)?;
+    for (file_id, full_filename) in &deleted {
+        stats.remove(file_id);
+        if snapshots.is_pinned(*file_id) {
+            snapshots.defer_delete(*file_id);
             continue;
         }
-        delete_file(&*full_filename)?;
-        count += 1;
+        delete_data_file(full_filename, *file_id, read_handles)?;
+        files.remove(*file_id);
     }
-    println!("Compacted {} files", count);
+    println!("Compacted {} files", deleted.len());
     Ok(())
 }
+
+fn delete_data_file(
+    full_filename: &str,
+    file_id: u32,
+    read_handles: &ReadHandles,
+) -> Result<(), Error> {
+    // the hint goes first, a hint without its data file would point to missing records
+    delete_hint_file(full_filename)?;
+    read_handles.remove(file_id);
+    delete_file(full_filename)
+}
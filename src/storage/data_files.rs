@@ -1,72 +1,372 @@
+use crate::storage::bit_cask::SyncPolicy;
+use crate::storage::crc::{crc32, Crc32};
+use crate::storage::lru::Lru;
+use crate::storage::mmap::Mmap;
 use std::fs;
-use std::fs::{File, OpenOptions};
-use std::io::{Error, Seek, SeekFrom, Write};
-use std::path::Path;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Error, ErrorKind, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-const FILE_MAX_OFFSET: u64 = 10_000_000;
+pub(crate) const DATA_FILE_PREFIX: &str = "data-file";
+pub(crate) const DEFAULT_MAX_OPEN_FILES: usize = 64;
+const LOCK_FILE: &str = "LOCK";
+// Written in place of the value length to mark a deleted key. Tombstones carry no value.
+pub(crate) const TOMBSTONE: u64 = u64::MAX;
+// Written in place of the expiry of a record that does not expire
+pub(crate) const NO_EXPIRY: u64 = 0;
+// Written in place of the value length of the markers around the records of an atomic batch
+pub(crate) const BATCH_BEGIN: u64 = u64::MAX - 1;
+pub(crate) const BATCH_COMMIT: u64 = u64::MAX - 2;
+// crc (4 bytes), sequence number (8 bytes), expiry (8 bytes), value length (8 bytes), key
+// length (8 bytes). The key and then the value follow the header
+pub(crate) const HEADER_SIZE: u64 = 4 + 8 + 8 + 8 + 8;
 
+// (filename, offset, value length, sequence number) of each record written
+pub(crate) type SavedRecord = (String, u64, usize, u64);
+
+// (sequence number, key, offset, value length, is tombstone) of a record in a data file
+pub(crate) type RecordInfo = (u64, Vec<u8>, u64, u64, bool);
+
+// (sequence number, key, value, expiry) of a record to write
+pub(crate) type SequencedRecord = (u64, Vec<u8>, Option<Vec<u8>>, u64);
+
+// A `None` value is written as a tombstone for the key. Once the current file grows past
+// `max_file_size`, writing goes on in the file returned by `new_file`.
+// The sequence number orders the versions of a key: new writes get the next one from the
+// store, and compaction copies keep the number of the original record, so a copied value
+// never looks newer than a write made during the merge.
+// With `atomic`, the records are framed by a begin and a commit marker and written to a single
+// file, starting a new one if they do not fit in the current one. On recovery, they are only
+// applied if the commit marker made it to disk.
+// Files sealed by a rollover are synced unless the policy leaves it to the OS
 pub(crate) fn save(
-    data_dir: &str,
     active_dir: &str,
-    data_vec: Vec<(usize, String)>,
-) -> Result<(Vec<(String, u64, usize, u64)>, String), Error> {
-    let mut file = OpenOptions::new()
-        .write(true)
-        .append(true)
-        .open(active_dir)?;
+    records: &[SequencedRecord],
+    atomic: bool,
+    sync_policy: SyncPolicy,
+    max_file_size: u64,
+    mut new_file: impl FnMut() -> Result<String, Error>,
+) -> Result<(Vec<SavedRecord>, String), Error> {
+    let mut file = OpenOptions::new().append(true).open(active_dir)?;
 
     let mut results = Vec::new();
     let mut offset = file.seek(SeekFrom::End(0))?;
     let mut current_active_dir = active_dir.to_string();
 
-    for (key, value) in data_vec {
-        let ts = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        file.write_all(&ts.to_be_bytes())?;
-        let v_length = value.len();
-        file.write_all(&v_length.to_be_bytes())?;
-        file.write_all(&key.to_be_bytes())?;
-        file.write_all(value.as_bytes())?;
-        results.push((current_active_dir.to_string(), offset, v_length, ts));
-        offset += 8 + 8 + 8 + v_length as u64;
-
-        if offset > FILE_MAX_OFFSET {
-            file.flush()?;
-            current_active_dir = create_new_active_file(data_dir)?;
-            file = OpenOptions::new()
-                .write(true)
-                .append(true)
-                .open(current_active_dir.to_string())?;
+    let batches: Vec<&[SequencedRecord]> = if atomic {
+        vec![records]
+    } else {
+        records.chunks(1).collect()
+    };
+    for batch in batches {
+        let (bytes, record_offsets) = encode_batch(batch, atomic);
+        // a batch larger than a whole file gets a file of its own
+        if atomic && offset > 0 && offset + bytes.len() as u64 > max_file_size {
+            current_active_dir = roll_over(&mut file, sync_policy, &mut new_file)?;
             offset = 0;
         }
+        file.write_all(&bytes)?;
+        for ((seq, _, value, _), record_offset) in batch.iter().zip(record_offsets) {
+            let v_length = value.as_ref().map_or(0, |v| v.len());
+            results.push((
+                current_active_dir.to_string(),
+                offset + record_offset,
+                v_length,
+                *seq,
+            ));
+        }
+        offset += bytes.len() as u64;
+
+        if offset > max_file_size {
+            current_active_dir = roll_over(&mut file, sync_policy, &mut new_file)?;
+            offset = 0;
+        }
+    }
+
+    if sync_policy == SyncPolicy::EveryWrite {
+        file.sync_data()?;
     }
 
     Ok((results, current_active_dir))
 }
 
-pub(crate) fn create_new_active_file(data_dir: &str) -> Result<String, Error> {
-    let filename = format!("{}/data-file{}", data_dir, get_random());
-    File::create(filename.clone())?;
-    Ok(filename.to_string())
+// Bytes taken by the records once written as a batch, markers included
+pub(crate) fn batch_size<'a>(records: impl Iterator<Item = (&'a [u8], Option<&'a [u8]>)>) -> u64 {
+    let records: u64 = records
+        .map(|(key, value)| HEADER_SIZE + (key.len() + value.map_or(0, |v| v.len())) as u64)
+        .sum();
+    records + 2 * HEADER_SIZE
+}
+
+// Seals the current file and opens the next one
+fn roll_over(
+    file: &mut File,
+    sync_policy: SyncPolicy,
+    new_file: &mut impl FnMut() -> Result<String, Error>,
+) -> Result<String, Error> {
+    file.flush()?;
+    if sync_policy != SyncPolicy::OsManaged {
+        file.sync_data()?;
+    }
+    let filename = new_file()?;
+    *file = OpenOptions::new().append(true).open(&filename)?;
+    Ok(filename)
+}
+
+fn encode_record(seq: u64, expires_at: u64, key: &[u8], value: Option<&[u8]>) -> Vec<u8> {
+    let mut record =
+        Vec::with_capacity(HEADER_SIZE as usize + key.len() + value.map_or(0, |v| v.len()));
+    record.extend_from_slice(&[0u8; 4]);
+    record.extend_from_slice(&seq.to_be_bytes());
+    record.extend_from_slice(&expires_at.to_be_bytes());
+    match value {
+        Some(value) => record.extend_from_slice(&value.len().to_be_bytes()),
+        None => record.extend_from_slice(&TOMBSTONE.to_be_bytes()),
+    }
+    record.extend_from_slice(&key.len().to_be_bytes());
+    record.extend_from_slice(key);
+    if let Some(value) = value {
+        record.extend_from_slice(value);
+    }
+    let crc = crc32(&record[4..]);
+    record[..4].copy_from_slice(&crc.to_be_bytes());
+    record
+}
+
+// The bytes to append for the records, and the offset of each record within them
+fn encode_batch(records: &[SequencedRecord], framed: bool) -> (Vec<u8>, Vec<u64>) {
+    let first_seq = records.first().map_or(0, |r| r.0);
+    let mut bytes = Vec::new();
+    let mut offsets = Vec::with_capacity(records.len());
+    if framed {
+        bytes.extend(encode_marker(BATCH_BEGIN, first_seq, records.len() as u64));
+    }
+    for (seq, key, value, expires_at) in records {
+        offsets.push(bytes.len() as u64);
+        bytes.extend(encode_record(*seq, *expires_at, key, value.as_deref()));
+    }
+    if framed {
+        bytes.extend(encode_marker(BATCH_COMMIT, first_seq, records.len() as u64));
+    }
+    (bytes, offsets)
 }
 
-pub(crate) fn create_new_file(full_filename: &str) -> Result<(), Error> {
-    let path = Path::new(full_filename);
-    if path.exists() {
-        fs::remove_file(path)?;
+// A header without key or value, with the first sequence number of the batch, and its number
+// of records in place of the expiry
+fn encode_marker(kind: u64, first_seq: u64, count: u64) -> Vec<u8> {
+    let mut marker = Vec::with_capacity(HEADER_SIZE as usize);
+    marker.extend_from_slice(&[0u8; 4]);
+    marker.extend_from_slice(&first_seq.to_be_bytes());
+    marker.extend_from_slice(&count.to_be_bytes());
+    marker.extend_from_slice(&kind.to_be_bytes());
+    marker.extend_from_slice(&0u64.to_be_bytes());
+    let crc = crc32(&marker[4..]);
+    marker[..4].copy_from_slice(&crc.to_be_bytes());
+    marker
+}
+
+// Reads (crc, sequence number, expiry, value length, key length) from a record header
+pub(crate) fn decode_header(header: &[u8]) -> (u32, u64, u64, u64, u64) {
+    let crc = u32::from_be_bytes(header[0..4].try_into().unwrap());
+    let seq = u64::from_be_bytes(header[4..12].try_into().unwrap());
+    let expires_at = u64::from_be_bytes(header[12..20].try_into().unwrap());
+    let v_length = u64::from_be_bytes(header[20..28].try_into().unwrap());
+    let k_length = u64::from_be_bytes(header[28..36].try_into().unwrap());
+    (crc, seq, expires_at, v_length, k_length)
+}
+
+// Expiries are milliseconds since the unix epoch
+pub(crate) fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+pub(crate) fn is_expired(expires_at: u64, now: u64) -> bool {
+    expires_at != NO_EXPIRY && expires_at <= now
+}
+
+// Returns an error if the record is corrupted. `body` is the key followed by the value
+pub(crate) fn check_record(header: &[u8], body: &[u8]) -> Result<(), Error> {
+    let (crc, _, _, _, _) = decode_header(header);
+    let mut computed = Crc32::new();
+    computed.update(&header[4..]);
+    computed.update(body);
+    if computed.finish() != crc {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Record checksum does not match",
+        ));
     }
-    File::create(full_filename)?;
     Ok(())
 }
 
-fn get_random() -> u128 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_nanos()
+// Data files sorted by name
+pub(crate) fn list_data_files(data_dir: &str) -> Result<Vec<String>, Error> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(data_dir)? {
+        let path = entry?.path();
+        let Some(filename) = path.file_name().and_then(|f| f.to_str()) else {
+            continue;
+        };
+        if filename.starts_with(DATA_FILE_PREFIX) {
+            files.push(format!("{}/{}", data_dir, filename));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// An open data file, mapped in memory if it was sealed when opened
+pub(crate) struct ReadHandle {
+    file: File,
+    map: Option<Mmap>,
+}
+
+impl ReadHandle {
+    // Fills `buf` from the offset in the file
+    pub(crate) fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<(), Error> {
+        let end = offset as usize + buf.len();
+        match self
+            .map
+            .as_ref()
+            .and_then(|m| m.as_slice().get(offset as usize..end))
+        {
+            Some(bytes) => {
+                buf.copy_from_slice(bytes);
+                Ok(())
+            }
+            None => read_at(&self.file, buf, offset),
+        }
+    }
+}
+
+// Data files kept open for reads, up to a number of them, closing the least recently used.
+// Files are only appended to, so a handle stays valid as the file grows. With `mmap`, every
+// file but the active one is mapped, as sealed files do not change anymore
+pub(crate) struct ReadHandles {
+    cache: Mutex<Lru<u32, Arc<ReadHandle>>>,
+    mmap: bool,
+    // id of the active file, u32::MAX for none
+    active_file: AtomicU32,
+}
+
+impl ReadHandles {
+    pub(crate) fn new(max_open_files: usize, mmap: bool) -> Self {
+        ReadHandles {
+            cache: Mutex::new(Lru::new(max_open_files)),
+            mmap,
+            active_file: AtomicU32::new(u32::MAX),
+        }
+    }
+
+    // Set before the key dir points to the new active file, so that it is never mapped
+    pub(crate) fn set_active_file(&self, file_id: u32) {
+        self.active_file.store(file_id, Ordering::SeqCst);
+    }
+
+    // The handle of the file with the id. If it is not open yet, the file named by `filename`
+    // is opened. A file opened while active is mapped once it is sealed
+    pub(crate) fn get(
+        &self,
+        file_id: u32,
+        filename: impl FnOnce() -> Result<String, Error>,
+    ) -> Result<Arc<ReadHandle>, Error> {
+        let map = self.mmap && self.active_file.load(Ordering::SeqCst) != file_id;
+        if let Some(handle) = self.cache.lock().unwrap().get(&file_id) {
+            if handle.map.is_some() || !map {
+                return Ok(Arc::clone(handle));
+            }
+        }
+        let file = File::open(filename()?)?;
+        let map = if map { Mmap::map(&file)? } else { None };
+        let handle = Arc::new(ReadHandle { file, map });
+        let mut cache = self.cache.lock().unwrap();
+        cache.insert(file_id, Arc::clone(&handle), 1);
+        Ok(handle)
+    }
+
+    // Closes the file, once the reads that hold it are done. Called when a file is deleted
+    pub(crate) fn remove(&self, file_id: u32) {
+        self.cache.lock().unwrap().remove(&file_id);
+    }
+}
+
+impl Default for ReadHandles {
+    fn default() -> Self {
+        ReadHandles::new(DEFAULT_MAX_OPEN_FILES, false)
+    }
+}
+
+// Fills `buf` from the offset in the file, without moving a cursor, so a handle can be shared
+// by concurrent reads
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> Result<(), Error> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileExt;
+        file.read_exact_at(buf, offset)
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::FileExt;
+        let mut read = 0;
+        while read < buf.len() {
+            match file.seek_read(&mut buf[read..], offset + read as u64)? {
+                0 => return Err(Error::from(ErrorKind::UnexpectedEof)),
+                n => read += n,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Locks the data dir for this process, until the returned file is closed. A store that writes
+// holds the lock alone, while read only stores share it. A read only store creates the file
+// when no writer ever did, so that it always holds the lock
+pub(crate) fn lock_data_dir(data_dir: &str, read_only: bool) -> Result<File, Error> {
+    let path = format!("{}/{}", data_dir, LOCK_FILE);
+    let create = || {
+        OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+    };
+    let file = if read_only {
+        // opened for reading when it exists, which a read only file system allows
+        match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => create()?,
+            Err(e) => return Err(e),
+        }
+    } else {
+        create()?
+    };
+    let locked = if read_only {
+        file.try_lock_shared()
+    } else {
+        file.try_lock()
+    };
+    match locked {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(Error::new(
+            ErrorKind::ResourceBusy,
+            format!("Data dir {} is already opened by another store", data_dir),
+        )),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+pub(crate) fn sync_file(full_filename: &str) -> Result<(), Error> {
+    File::open(full_filename)?.sync_data()
+}
+
+pub(crate) fn truncate_file(full_filename: &str, length: u64) -> Result<(), Error> {
+    let file = OpenOptions::new().write(true).open(full_filename)?;
+    file.set_len(length)?;
+    file.sync_all()?;
+    Ok(())
 }
 
 pub(crate) fn delete_file(full_filename: &str) -> Result<(), Error> {
@@ -0,0 +1,116 @@
+use crate::storage::crc::crc32;
+use crate::storage::data_files::{RecordInfo, DATA_FILE_PREFIX, TOMBSTONE};
+use std::fs;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::Path;
+
+// Each sealed data file gets a hint file, with the same suffix, listing its records
+pub(crate) const HINT_FILE_PREFIX: &str = "hint-file";
+const TEMP_SUFFIX: &str = ".tmp";
+// record count (8 bytes) and crc (4 bytes) of everything before the crc
+const TRAILER_SIZE: usize = 8 + 4;
+
+// The hint file of a data file. E.g. data-dir/data-file123 -> data-dir/hint-file123
+pub(crate) fn hint_filename(data_filename: &str) -> String {
+    let path = Path::new(data_filename);
+    let name = path.file_name().and_then(|f| f.to_str()).unwrap_or("");
+    let suffix = name.strip_prefix(DATA_FILE_PREFIX).unwrap_or(name);
+    path.with_file_name(format!("{}{}", HINT_FILE_PREFIX, suffix))
+        .to_string_lossy()
+        .to_string()
+}
+
+// Layout: compacted flag (1 byte), then for each record: sequence number (8 bytes), value length
+// or TOMBSTONE (8 bytes), key length (8 bytes), key, offset (8 bytes), and the trailer.
+// The hint is written to a temporary file which is then renamed, so a crash never leaves a
+// partial hint behind.
+// `compacted` marks files written by a compaction, whose records are copies of older ones
+pub(crate) fn write_hint_file(
+    data_filename: &str,
+    records: &[RecordInfo],
+    compacted: bool,
+) -> Result<(), Error> {
+    let mut content = vec![compacted as u8];
+    for (seq, key, offset, v_length, tombstone) in records {
+        let length = if *tombstone { TOMBSTONE } else { *v_length };
+        content.extend_from_slice(&seq.to_be_bytes());
+        content.extend_from_slice(&length.to_be_bytes());
+        content.extend_from_slice(&(key.len() as u64).to_be_bytes());
+        content.extend_from_slice(key);
+        content.extend_from_slice(&offset.to_be_bytes());
+    }
+    content.extend_from_slice(&(records.len() as u64).to_be_bytes());
+    let crc = crc32(&content);
+    content.extend_from_slice(&crc.to_be_bytes());
+
+    let filename = hint_filename(data_filename);
+    let temp_filename = format!("{}{}", filename, TEMP_SUFFIX);
+    let mut file = File::create(&temp_filename)?;
+    file.write_all(&content)?;
+    file.sync_all()?;
+    fs::rename(&temp_filename, &filename)
+}
+
+// Whether the hint of the data file is from a compaction. Only reads the first byte, the hint
+// is checked when it is read
+pub(crate) fn is_compacted(data_filename: &str) -> bool {
+    let mut flag = [0u8; 1];
+    File::open(hint_filename(data_filename))
+        .and_then(|mut f| f.read_exact(&mut flag))
+        .is_ok_and(|_| flag[0] == 1)
+}
+
+// Returns the records of the data file. Fails with InvalidData if the hint is damaged
+pub(crate) fn read_hint_file(data_filename: &str) -> Result<Vec<RecordInfo>, Error> {
+    let content = fs::read(hint_filename(data_filename))?;
+    let invalid = |reason: &str| Error::new(ErrorKind::InvalidData, reason.to_string());
+    if content.len() < 1 + TRAILER_SIZE {
+        return Err(invalid("Hint file is too short"));
+    }
+    let (body, crc) = content.split_at(content.len() - 4);
+    if crc32(body) != u32::from_be_bytes(crc.try_into().unwrap()) {
+        return Err(invalid("Hint file checksum does not match"));
+    }
+    let (entries, count) = body.split_at(body.len() - 8);
+    let count = u64::from_be_bytes(count.try_into().unwrap());
+
+    let mut records = Vec::new();
+    let mut pos = 1;
+    let read_u64 = |pos: &mut usize| -> Result<u64, Error> {
+        let bytes = entries
+            .get(*pos..*pos + 8)
+            .ok_or_else(|| invalid("Hint file entry is incomplete"))?;
+        *pos += 8;
+        Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+    };
+    while pos < entries.len() {
+        let seq = read_u64(&mut pos)?;
+        let v_length = read_u64(&mut pos)?;
+        let k_length = read_u64(&mut pos)? as usize;
+        let key = entries
+            .get(pos..pos + k_length)
+            .ok_or_else(|| invalid("Hint file entry is incomplete"))?
+            .to_vec();
+        pos += k_length;
+        let offset = read_u64(&mut pos)?;
+        let tombstone = v_length == TOMBSTONE;
+        let v_length = if tombstone { 0 } else { v_length };
+        records.push((seq, key, offset, v_length, tombstone));
+    }
+    if records.len() as u64 != count {
+        return Err(invalid("Hint file record count does not match"));
+    }
+    Ok(records)
+}
+
+pub(crate) fn has_hint_file(data_filename: &str) -> bool {
+    Path::new(&hint_filename(data_filename)).exists()
+}
+
+pub(crate) fn delete_hint_file(data_filename: &str) -> Result<(), Error> {
+    match fs::remove_file(hint_filename(data_filename)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,108 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// Threads that read data files for range reads, shared by every handle of a store, so reads
+// do not start threads of their own. Jobs run in the order they are queued. Without threads,
+// they run on the caller. The threads stop once the pool is dropped, after the queued jobs
+#[derive(Default)]
+pub(crate) struct IoPool {
+    jobs: Option<Sender<Job>>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl IoPool {
+    pub(crate) fn new(threads: usize) -> Self {
+        if threads == 0 {
+            return Default::default();
+        }
+        let (tx, rx) = channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        let handles = (0..threads)
+            .map(|_| {
+                let rx = Arc::clone(&rx);
+                thread::spawn(move || run_jobs(&rx))
+            })
+            .collect();
+        IoPool {
+            jobs: Some(tx),
+            handles,
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn threads(&self) -> usize {
+        self.handles.len()
+    }
+
+    // A job that panics is dropped, with whatever it owns, and the thread goes on with the
+    // next one
+    pub(crate) fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        match &self.jobs {
+            Some(jobs) => {
+                // the threads only stop once the pool is dropped
+                jobs.send(Box::new(job)).unwrap();
+            }
+            None => {
+                let _ = catch_unwind(AssertUnwindSafe(job));
+            }
+        }
+    }
+}
+
+fn run_jobs(jobs: &Mutex<Receiver<Job>>) {
+    loop {
+        // the queue is only locked while waiting for a job, not while running it
+        let job = jobs.lock().unwrap().recv();
+        match job {
+            Ok(job) => {
+                let _ = catch_unwind(AssertUnwindSafe(job));
+            }
+            Err(_) => return,
+        }
+    }
+}
+
+impl Drop for IoPool {
+    fn drop(&mut self) {
+        self.jobs.take();
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_io_pool() {
+        for threads in [0, 1, 3] {
+            let pool = IoPool::new(threads);
+            assert_eq!(threads, pool.threads());
+            let (tx, rx) = channel();
+            for i in 0..20 {
+                let tx = tx.clone();
+                pool.execute(move || {
+                    if i == 5 {
+                        panic!("failed job");
+                    }
+                    tx.send((i, thread::current().id())).unwrap();
+                });
+            }
+            drop(tx);
+            let done: Vec<_> = rx.iter().collect();
+            assert_eq!(19, done.len());
+            // no thread per job, and the caller's own without threads
+            let used: HashSet<_> = done.iter().map(|(_, id)| *id).collect();
+            assert!(used.len() <= threads.max(1));
+            assert_eq!(threads == 0, used.contains(&thread::current().id()));
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use crate::storage::data_files::HEADER_SIZE;
+use crate::storage::paged_index::PagedIndex;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Error, ErrorKind};
+use std::ops::Bound;
+
+// Value length stored for a tombstone. Values are limited to less than 4 GB
+pub(crate) const TOMBSTONE_LENGTH: u32 = u32::MAX;
+
+// Where the record of a key is, and its sequence number. The file is an id from the
+// `FileTable`, so that every entry has the same small size.
+// A deleted key keeps pointing to its tombstone until a full compaction drops it, so the
+// tombstone is carried over by compactions that leave older values of the key on disk
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Key {
+    pub(crate) seq: u64,
+    pub(crate) offset: u64,
+    pub(crate) file_id: u32,
+    length: u32,
+}
+
+impl Key {
+    pub(crate) fn new(file_id: u32, seq: u64, offset: u64, length: u32) -> Self {
+        Key {
+            seq,
+            offset,
+            file_id,
+            length,
+        }
+    }
+
+    pub(crate) fn new_tombstone(file_id: u32, seq: u64, offset: u64) -> Self {
+        Key::new(file_id, seq, offset, TOMBSTONE_LENGTH)
+    }
+
+    pub(crate) fn is_tombstone(&self) -> bool {
+        self.length == TOMBSTONE_LENGTH
+    }
+
+    // Length of the value, 0 for a tombstone
+    pub(crate) fn length(&self) -> usize {
+        if self.is_tombstone() {
+            0
+        } else {
+            self.length as usize
+        }
+    }
+
+    // Whether both point to the same record
+    pub(crate) fn same_location(&self, other: &Key) -> bool {
+        self.offset == other.offset && self.file_id == other.file_id
+    }
+
+    // Bytes taken by the record in its data file
+    pub(crate) fn record_size(&self, key: &[u8]) -> u64 {
+        HEADER_SIZE + key.len() as u64 + self.length() as u64
+    }
+}
+
+// Numeric ids of the data files. Ids are given in the order files are seen and never reused,
+// so a key cannot end up pointing to a newer file with an old id
+#[derive(Default)]
+pub(crate) struct FileTable {
+    ids: HashMap<String, u32>,
+    filenames: HashMap<u32, String>,
+    next_id: u32,
+}
+
+impl FileTable {
+    // The id of the file, given a new one if it has none yet
+    pub(crate) fn id(&mut self, filename: &str) -> u32 {
+        if let Some(id) = self.ids.get(filename) {
+            return *id;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.ids.insert(filename.to_string(), id);
+        self.filenames.insert(id, filename.to_string());
+        id
+    }
+
+    pub(crate) fn filename(&self, id: u32) -> Result<String, Error> {
+        self.filenames
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("No data file with id {}", id)))
+    }
+
+    pub(crate) fn remove(&mut self, id: u32) {
+        if let Some(filename) = self.filenames.remove(&id) {
+            self.ids.remove(&filename);
+        }
+    }
+}
+
+// The keys of the store, ordered. Either all in memory, or in a paged index on disk for key
+// sets larger than the memory
+pub(crate) enum KeyDir {
+    Memory(BTreeMap<Box<[u8]>, Key>),
+    Paged(PagedIndex),
+}
+
+impl Default for KeyDir {
+    fn default() -> Self {
+        KeyDir::Memory(BTreeMap::new())
+    }
+}
+
+impl KeyDir {
+    pub(crate) fn get(&mut self, key: &[u8]) -> Result<Option<Key>, Error> {
+        match self {
+            KeyDir::Memory(map) => Ok(map.get(key).copied()),
+            KeyDir::Paged(index) => index.get(key),
+        }
+    }
+
+    // Returns the previous location of the key
+    pub(crate) fn insert(&mut self, key: Vec<u8>, value: Key) -> Result<Option<Key>, Error> {
+        match self {
+            KeyDir::Memory(map) => Ok(map.insert(key.into_boxed_slice(), value)),
+            KeyDir::Paged(index) => index.insert(key, value),
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: &[u8]) -> Result<Option<Key>, Error> {
+        match self {
+            KeyDir::Memory(map) => Ok(map.remove(key)),
+            KeyDir::Paged(index) => index.remove(key),
+        }
+    }
+
+    // Calls `f` with the keys in the range, in order or in reverse order, until it returns false
+    pub(crate) fn scan(
+        &mut self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        reverse: bool,
+        mut f: impl FnMut(&[u8], &Key) -> bool,
+    ) -> Result<(), Error> {
+        match self {
+            KeyDir::Memory(map) => {
+                let range = map.range::<[u8], _>((start, end));
+                let entries: Box<dyn Iterator<Item = _>> = if reverse {
+                    Box::new(range.rev())
+                } else {
+                    Box::new(range)
+                };
+                for (k, v) in entries {
+                    if !f(k, v) {
+                        break;
+                    }
+                }
+                Ok(())
+            }
+            KeyDir::Paged(index) => index.scan(start, end, reverse, f),
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+// Least recently used cache bounded by the total size of its entries
+pub(crate) struct Lru<K, V> {
+    capacity: usize,
+    size: usize,
+    tick: u64,
+    // value, size and last use of each entry
+    entries: HashMap<K, (V, usize, u64)>,
+    // entries by last use, oldest first
+    order: BTreeMap<u64, K>,
+}
+
+impl<K: Hash + Eq + Clone, V> Lru<K, V> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Lru {
+            capacity,
+            size: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn get<Q: Hash + Eq + ?Sized>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+    {
+        let (owned_key, (_, _, last_use)) = self.entries.get_key_value(key)?;
+        let (owned_key, last_use) = (owned_key.clone(), *last_use);
+        self.order.remove(&last_use);
+        self.tick += 1;
+        self.order.insert(self.tick, owned_key);
+        let (value, _, last_use) = self.entries.get_mut(key)?;
+        *last_use = self.tick;
+        Some(value)
+    }
+
+    // Evicts the least recently used entries until the new one fits. Returns how many were
+    // evicted. An entry larger than the whole cache is not kept
+    pub(crate) fn insert(&mut self, key: K, value: V, size: usize) -> usize {
+        self.remove(&key);
+        if size > self.capacity {
+            return 0;
+        }
+        let mut evicted = 0;
+        while self.size + size > self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some((_, s, _)) = self.entries.remove(&oldest) {
+                self.size -= s;
+            }
+            evicted += 1;
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, size, self.tick));
+        self.size += size;
+        evicted
+    }
+
+    pub(crate) fn remove<Q: Hash + Eq + ?Sized>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+    {
+        let (value, size, last_use) = self.entries.remove(key)?;
+        self.order.remove(&last_use);
+        self.size -= size;
+        Some(value)
+    }
+
+    // Total size of the entries
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru() {
+        let mut lru = Lru::new(10);
+        lru.insert(1, "one", 4);
+        lru.insert(2, "two", 4);
+        assert_eq!(Some(&"one"), lru.get(&1));
+
+        // 2 is the least recently used
+        assert_eq!(1, lru.insert(3, "three", 4));
+        assert_eq!(None, lru.get(&2));
+        assert_eq!(Some(&"one"), lru.get(&1));
+        assert_eq!(Some(&"three"), lru.get(&3));
+        assert_eq!(8, lru.size());
+
+        assert_eq!(0, lru.insert(4, "too big", 11));
+        assert_eq!(None, lru.get(&4));
+        assert_eq!(Some("one"), lru.remove(&1));
+        assert_eq!(4, lru.size());
+    }
+}
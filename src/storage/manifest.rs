@@ -0,0 +1,254 @@
+use crate::storage::crc::crc32;
+use crate::storage::data_files::{list_data_files, DATA_FILE_PREFIX};
+use crate::storage::hint_files::{delete_hint_file, hint_filename, HINT_FILE_PREFIX};
+use std::cmp::max;
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Write};
+use std::path::Path;
+
+const MANIFEST_FILE: &str = "MANIFEST";
+const TEMP_SUFFIX: &str = ".tmp";
+
+// The data files of a store. Files are numbered in the order they are created:
+// data-file0000000000, data-file0000000001...
+// A file is created only once the manifest lists it, and the manifest is replaced as a whole
+// by writing a temporary file and renaming it, so after a crash it names every file that may
+// hold data. Anything else in the data dir is left over by an interrupted write or merge
+#[derive(Default)]
+pub(crate) struct Manifest {
+    data_dir: String,
+    next_file_number: u64,
+    // file names, in the order they were added. The active file is among them
+    files: Vec<String>,
+    active: Option<String>,
+    // files being written by a compaction, which only count once it installs them
+    merge_outputs: Vec<String>,
+    // a sequence number above any a compaction dropped
+    next_seq: u64,
+    read_only: bool,
+}
+
+impl Manifest {
+    // Reads the manifest of the data dir. Without one, the data files found are taken in name
+    // order, the newest one as the active file
+    pub(crate) fn open(data_dir: &str, read_only: bool) -> Result<Self, Error> {
+        let path = format!("{}/{}", data_dir, MANIFEST_FILE);
+        let mut manifest = match fs::read(&path) {
+            Ok(content) => decode(data_dir, &content)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let files: Vec<String> = list_data_files(data_dir)?
+                    .iter()
+                    .map(|f| file_name(f).to_string())
+                    .collect();
+                Manifest {
+                    data_dir: data_dir.to_string(),
+                    next_file_number: 0,
+                    active: files.last().cloned(),
+                    files,
+                    merge_outputs: Vec::new(),
+                    next_seq: 0,
+                    read_only,
+                }
+            }
+            Err(e) => return Err(e),
+        };
+        manifest.read_only = read_only;
+        Ok(manifest)
+    }
+
+    // Data files, oldest first, with their full path
+    pub(crate) fn data_files(&self) -> Vec<String> {
+        self.files.iter().map(|f| self.full_path(f)).collect()
+    }
+
+    // The file that was being written when the store last stopped
+    pub(crate) fn active_file(&self) -> Option<String> {
+        self.active.as_ref().map(|f| self.full_path(f))
+    }
+
+    pub(crate) fn new_active_file(&mut self) -> Result<String, Error> {
+        let filename = self.next_filename();
+        self.files.push(filename.clone());
+        self.active = Some(filename.clone());
+        self.save()?;
+        self.create(&filename)
+    }
+
+    // A new file for the output of a compaction
+    pub(crate) fn new_merge_output(&mut self) -> Result<String, Error> {
+        let filename = self.next_filename();
+        self.merge_outputs.push(filename.clone());
+        self.save()?;
+        self.create(&filename)
+    }
+
+    // Adds the outputs of the running compaction to the data files, and drops the merged
+    // files about to be deleted, in a single update. `next_seq` is where sequence numbers
+    // continue from, whatever records the compaction dropped
+    pub(crate) fn install_merge(&mut self, removed: &[String], next_seq: u64) -> Result<(), Error> {
+        let removed: HashSet<&str> = removed.iter().map(|f| file_name(f)).collect();
+        self.files.retain(|f| !removed.contains(f.as_str()));
+        self.files.append(&mut self.merge_outputs);
+        self.next_seq = max(self.next_seq, next_seq);
+        self.save()
+    }
+
+    pub(crate) fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    // Deletes what is in the data dir but not in the manifest: the outputs of a compaction
+    // that did not finish, files left by a compaction that stopped before deleting them, and
+    // temporary hint files
+    pub(crate) fn remove_stray_files(&mut self) -> Result<(), Error> {
+        for filename in std::mem::take(&mut self.merge_outputs) {
+            let full_filename = self.full_path(&filename);
+            println!("Deleting unfinished merge output {}", full_filename);
+            delete_hint_file(&full_filename)?;
+            remove_if_exists(&full_filename)?;
+        }
+        self.save()?;
+
+        let listed: HashSet<String> = self
+            .data_files()
+            .iter()
+            .map(|f| file_name(&hint_filename(f)).to_string())
+            .chain(self.files.iter().cloned())
+            .collect();
+        for entry in fs::read_dir(&self.data_dir)? {
+            let path = entry?.path();
+            let Some(filename) = path.file_name().and_then(|f| f.to_str()) else {
+                continue;
+            };
+            let is_store_file =
+                filename.starts_with(DATA_FILE_PREFIX) || filename.starts_with(HINT_FILE_PREFIX);
+            if is_store_file && !listed.contains(filename) {
+                println!("Deleting stray file {}", path.display());
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn next_filename(&mut self) -> String {
+        loop {
+            let filename = format!("{}{:010}", DATA_FILE_PREFIX, self.next_file_number);
+            self.next_file_number += 1;
+            // a dir without a manifest may already hold numbered files
+            if !self.files.contains(&filename) && !Path::new(&self.full_path(&filename)).exists() {
+                return filename;
+            }
+        }
+    }
+
+    fn create(&self, filename: &str) -> Result<String, Error> {
+        let full_filename = self.full_path(filename);
+        File::create(&full_filename)?;
+        Ok(full_filename)
+    }
+
+    fn full_path(&self, filename: &str) -> String {
+        format!("{}/{}", self.data_dir, filename)
+    }
+
+    // Layout: one line per entry ("next <number>", "seq <number>", "active <file>",
+    // "file <file>" and "merge <file>"), and a last line with the crc of the ones before it
+    fn save(&self) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "Storage is opened as read only",
+            ));
+        }
+        let mut content = format!("next {}\n", self.next_file_number);
+        content.push_str(&format!("seq {}\n", self.next_seq));
+        if let Some(active) = &self.active {
+            content.push_str(&format!("active {}\n", active));
+        }
+        for filename in &self.files {
+            content.push_str(&format!("file {}\n", filename));
+        }
+        for filename in &self.merge_outputs {
+            content.push_str(&format!("merge {}\n", filename));
+        }
+        content.push_str(&format!("crc {}\n", crc32(content.as_bytes())));
+
+        let path = self.full_path(MANIFEST_FILE);
+        let temp_path = format!("{}{}", path, TEMP_SUFFIX);
+        let mut file = File::create(&temp_path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &path)?;
+        // the rename itself is only durable once the dir is synced. Directories cannot be
+        // opened as files on windows, so there it is left to the file system
+        #[cfg(unix)]
+        File::open(&self.data_dir)?.sync_all()?;
+        Ok(())
+    }
+}
+
+fn decode(data_dir: &str, content: &[u8]) -> Result<Manifest, Error> {
+    let invalid = |reason: &str| Error::new(ErrorKind::InvalidData, reason.to_string());
+    let content = std::str::from_utf8(content).map_err(|_| invalid("Manifest is not text"))?;
+    let body_end = content
+        .trim_end_matches('\n')
+        .rfind('\n')
+        .map_or(0, |i| i + 1);
+    let (body, crc_line) = content.split_at(body_end);
+    let crc = crc_line
+        .trim_end()
+        .strip_prefix("crc ")
+        .and_then(|c| c.parse::<u32>().ok())
+        .ok_or_else(|| invalid("Manifest has no checksum"))?;
+    if crc32(body.as_bytes()) != crc {
+        return Err(invalid("Manifest checksum does not match"));
+    }
+
+    let mut manifest = Manifest {
+        data_dir: data_dir.to_string(),
+        next_file_number: 0,
+        files: Vec::new(),
+        active: None,
+        merge_outputs: Vec::new(),
+        next_seq: 0,
+        read_only: false,
+    };
+    for line in body.lines() {
+        let (kind, value) = line
+            .split_once(' ')
+            .ok_or_else(|| invalid("Manifest line is incomplete"))?;
+        match kind {
+            "next" => {
+                manifest.next_file_number = value
+                    .parse()
+                    .map_err(|_| invalid("Manifest file number is not a number"))?
+            }
+            "seq" => {
+                manifest.next_seq = value
+                    .parse()
+                    .map_err(|_| invalid("Manifest sequence number is not a number"))?
+            }
+            "active" => manifest.active = Some(value.to_string()),
+            "file" => manifest.files.push(value.to_string()),
+            "merge" => manifest.merge_outputs.push(value.to_string()),
+            _ => return Err(invalid("Manifest line is unknown")),
+        }
+    }
+    Ok(manifest)
+}
+
+fn file_name(full_filename: &str) -> &str {
+    Path::new(full_filename)
+        .file_name()
+        .and_then(|f| f.to_str())
+        .unwrap_or(full_filename)
+}
+
+fn remove_if_exists(full_filename: &str) -> Result<(), Error> {
+    match fs::remove_file(full_filename) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,106 @@
+use std::fs::File;
+use std::io::Error;
+
+// Read only map of a whole file, for files that no longer change. Only on unix, elsewhere
+// files are never mapped
+pub(crate) struct Mmap {
+    ptr: *const u8,
+    len: usize,
+}
+
+// the mapping is read only, and unmapped once, when dropped
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+#[cfg(unix)]
+mod sys {
+    use std::os::raw::{c_int, c_void};
+
+    pub(super) const PROT_READ: c_int = 1;
+    pub(super) const MAP_SHARED: c_int = 1;
+    pub(super) const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+    extern "C" {
+        // off_t is as wide as a pointer on the platforms built for
+        pub(super) fn mmap(
+            addr: *mut c_void,
+            len: usize,
+            prot: c_int,
+            flags: c_int,
+            fd: c_int,
+            offset: isize,
+        ) -> *mut c_void;
+        pub(super) fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    }
+}
+
+impl Mmap {
+    // Maps the file as it is now. `None` for an empty file, which cannot be mapped
+    #[cfg(unix)]
+    pub(crate) fn map(file: &File) -> Result<Option<Mmap>, Error> {
+        use std::os::unix::io::AsRawFd;
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            return Ok(None);
+        }
+        let ptr = unsafe {
+            sys::mmap(
+                std::ptr::null_mut(),
+                len,
+                sys::PROT_READ,
+                sys::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == sys::MAP_FAILED {
+            return Err(Error::last_os_error());
+        }
+        Ok(Some(Mmap {
+            ptr: ptr as *const u8,
+            len,
+        }))
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn map(_file: &File) -> Result<Option<Mmap>, Error> {
+        Ok(None)
+    }
+
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        unsafe {
+            sys::munmap(self.ptr as *mut _, self.len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+
+    #[test]
+    fn test_mmap() {
+        let filename = "test-data-mmap";
+        let mut file = File::create(filename).unwrap();
+        assert!(Mmap::map(&file).unwrap().is_none());
+
+        file.write_all(b"mapped bytes").unwrap();
+        let file = File::open(filename).unwrap();
+        let map = Mmap::map(&file).unwrap();
+        if cfg!(unix) {
+            assert_eq!(b"mapped bytes", map.unwrap().as_slice());
+        } else {
+            assert!(map.is_none());
+        }
+        fs::remove_file(filename).unwrap();
+    }
+}
@@ -1,19 +1,139 @@
 mod benchmark;
 pub mod bit_cask;
+mod crc;
 mod data_files;
+mod hint_files;
+mod io_pool;
+mod key_dir;
+mod lru;
+mod manifest;
+mod mmap;
+mod paged_index;
+mod snapshots;
+mod value_cache;
 
+use std::fmt;
+use std::fmt::Formatter;
 use std::io::Error;
+use std::ops::Bound;
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct KV {
-    pub key: usize,
-    pub value: String,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
 }
 
+impl fmt::Debug for KV {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KV")
+            .field("key", &String::from_utf8_lossy(&self.key))
+            .field("value", &String::from_utf8_lossy(&self.value))
+            .finish()
+    }
+}
+
+// Keys to read from a range: from `start` to `end`, each bound included, excluded or open, in
+// order or in reverse order, up to a limit. A range too large to read at once is read in pages,
+// each query continuing after the cursor of the previous page
+#[derive(Clone, Debug)]
+pub struct RangeQuery {
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    limit: Option<usize>,
+    reverse: bool,
+    after: Option<Vec<u8>>,
+}
+
+impl RangeQuery {
+    pub fn new(start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Self {
+        RangeQuery {
+            start,
+            end,
+            limit: None,
+            reverse: false,
+            after: None,
+        }
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    // From `end` down to `start`
+    pub fn reverse(mut self, reverse: bool) -> Self {
+        self.reverse = reverse;
+        self
+    }
+
+    // Only the keys that come after the cursor, in the order of the query
+    pub fn after(mut self, cursor: Vec<u8>) -> Self {
+        self.after = Some(cursor);
+        self
+    }
+
+    // The bounds left once the cursor is applied
+    pub(crate) fn bounds(&self) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+        let mut bounds = (self.start.clone(), self.end.clone());
+        if let Some(cursor) = &self.after {
+            let narrowed = if self.reverse {
+                &mut bounds.1
+            } else {
+                &mut bounds.0
+            };
+            // a cursor outside of the range leaves it empty or as it is
+            let inside = match (&*narrowed, self.reverse) {
+                (Bound::Unbounded, _) => true,
+                (Bound::Included(b) | Bound::Excluded(b), false) => cursor >= b,
+                (Bound::Included(b) | Bound::Excluded(b), true) => cursor <= b,
+            };
+            if inside {
+                *narrowed = Bound::Excluded(cursor.clone());
+            }
+        }
+        bounds
+    }
+}
+
+// The keys read by a `RangeQuery`, in its order. With a cursor, more keys may follow
+pub struct RangePage {
+    pub kvs: Vec<KV>,
+    pub cursor: Option<Vec<u8>>,
+}
+
+// Key-value pairs streamed by `KVStorage::iter`, in the order of the query
+pub type KVIter = Box<dyn Iterator<Item = Result<KV, Error>> + Send>;
+
+// What a conditional write expects the key to be, for optimistic concurrency. Versions come
+// from `get_versioned`: every write of a key gives it a new, higher version
+#[derive(Clone, Debug, PartialEq)]
+pub enum Condition {
+    // the key is not set, e.g. to create it only once
+    Absent,
+    Version(u64),
+    Value(Vec<u8>),
+}
+
+// Keys and values are arbitrary byte strings. Keys are ordered lexicographically
 pub trait KVStorage {
-    fn get(&self, key: usize) -> Result<String, Error>;
-    fn put(&mut self, key: usize, value: String) -> Result<(), Error>;
-    fn delete(&mut self, key: usize) -> Result<(), Error>;
-    fn range(&self, start: usize, end: usize) -> Result<Vec<KV>, Error>;
+    fn get(&self, key: &[u8]) -> Result<Vec<u8>, Error>;
+    // The value of the key and its version, `None` if the key is not set
+    fn get_versioned(&self, key: &[u8]) -> Result<Option<(Vec<u8>, u64)>, Error>;
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Error>;
+    fn delete(&mut self, key: &[u8]) -> Result<(), Error>;
+    // The keys from `start` to `end`, both included, in order
+    fn range(&self, start: &[u8], end: &[u8]) -> Result<Vec<KV>, Error>;
+    fn scan(&self, query: &RangeQuery) -> Result<RangePage, Error>;
+    // Streams the keys of the query, reading values as they are needed
+    fn iter(&self, query: &RangeQuery) -> KVIter;
     fn batch_put(&mut self, kvs: Vec<KV>) -> Result<(), Error>;
+    // Conditional writes only change the key if it meets the condition, checked atomically
+    // with the write. They return whether it did
+    fn put_if(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        condition: &Condition,
+    ) -> Result<bool, Error>;
+    fn delete_if(&mut self, key: &[u8], condition: &Condition) -> Result<bool, Error>;
 }
@@ -0,0 +1,603 @@
+use crate::storage::key_dir::{Key, TOMBSTONE_LENGTH};
+use crate::storage::lru::Lru;
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Entries are written in pages of about this size. Only the first key of each page is kept
+// in memory
+const PAGE_SIZE: usize = 4096;
+pub(crate) const DEFAULT_BUFFERED_KEYS: usize = 50_000;
+
+const INDEX_DIR_PREFIX: &str = "key-index";
+
+// A key, and its location or `None` if it was removed
+type Entry = (Vec<u8>, Option<Key>);
+type Page = Vec<Entry>;
+
+#[derive(Clone)]
+struct PageInfo {
+    first_key: Vec<u8>,
+    offset: u64,
+    length: u64,
+}
+
+// A sorted file of entries, split in pages
+struct Run {
+    id: u64,
+    path: String,
+    file: File,
+    entries: usize,
+    pages: Vec<PageInfo>,
+}
+
+// Key dir kept on disk, for key sets that do not fit in memory.
+// Changes are buffered in memory and written as sorted runs once the buffer is full. Newer runs
+// hide the entries of older ones, and runs of a similar size are merged, so there are only a
+// few of them to look at. Pages read are kept in a cache bounded by `cache_bytes`.
+// The index is rebuilt every time the store is opened, so its files are deleted with it
+pub(crate) struct PagedIndex {
+    dir: String,
+    buffer: BTreeMap<Vec<u8>, Option<Key>>,
+    max_buffered: usize,
+    // oldest first
+    runs: Vec<Run>,
+    next_run_id: u64,
+    cache: Lru<(u64, usize), Arc<Page>>,
+}
+
+impl PagedIndex {
+    pub(crate) fn new(dir: &str, cache_bytes: usize, max_buffered: usize) -> Result<Self, Error> {
+        if let Err(e) = fs::remove_dir_all(dir) {
+            if e.kind() != ErrorKind::NotFound {
+                return Err(e);
+            }
+        }
+        fs::create_dir_all(dir)?;
+        Ok(PagedIndex {
+            dir: dir.to_string(),
+            buffer: BTreeMap::new(),
+            max_buffered: max(max_buffered, 1),
+            runs: Vec::new(),
+            next_run_id: 0,
+            cache: Lru::new(cache_bytes),
+        })
+    }
+
+    pub(crate) fn get(&mut self, key: &[u8]) -> Result<Option<Key>, Error> {
+        if let Some(v) = self.buffer.get(key) {
+            return Ok(*v);
+        }
+        for i in (0..self.runs.len()).rev() {
+            let run = &self.runs[i];
+            let p = run.pages.partition_point(|p| p.first_key.as_slice() <= key);
+            if p == 0 {
+                continue;
+            }
+            let page = self.page(i, p - 1)?;
+            if let Ok(pos) = page.binary_search_by(|(k, _)| k.as_slice().cmp(key)) {
+                return Ok(page[pos].1);
+            }
+        }
+        Ok(None)
+    }
+
+    pub(crate) fn insert(&mut self, key: Vec<u8>, value: Key) -> Result<Option<Key>, Error> {
+        let old = self.get(&key)?;
+        self.buffer.insert(key, Some(value));
+        self.flush_if_full()?;
+        Ok(old)
+    }
+
+    pub(crate) fn remove(&mut self, key: &[u8]) -> Result<Option<Key>, Error> {
+        let old = self.get(key)?;
+        if old.is_some() {
+            self.buffer.insert(key.to_vec(), None);
+            self.flush_if_full()?;
+        }
+        Ok(old)
+    }
+
+    // Calls `f` with the keys in the range, in order or in reverse order, until it returns false
+    pub(crate) fn scan(
+        &mut self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        reverse: bool,
+        mut f: impl FnMut(&[u8], &Key) -> bool,
+    ) -> Result<(), Error> {
+        // newest first, so the merge keeps the latest entry of each key
+        let buffered = self
+            .buffer
+            .range::<[u8], _>((start, end))
+            .map(|(k, v)| Ok((k.clone(), *v)));
+        let mut sources: Vec<Box<dyn Iterator<Item = Result<Entry, Error>> + '_>> = if reverse {
+            vec![Box::new(buffered.rev())]
+        } else {
+            vec![Box::new(buffered)]
+        };
+        let from = if reverse { end } else { start };
+        for run in self.runs.iter().rev() {
+            sources.push(Box::new(RunIter::new(run, from, reverse)?));
+        }
+        for entry in MergeIter::new(sources, reverse) {
+            let (k, v) = entry?;
+            let past_end = match (reverse, start, end) {
+                (false, _, Bound::Included(end)) => k.as_slice() > end,
+                (false, _, Bound::Excluded(end)) => k.as_slice() >= end,
+                (true, Bound::Included(start), _) => k.as_slice() < start,
+                (true, Bound::Excluded(start), _) => k.as_slice() <= start,
+                _ => false,
+            };
+            if past_end {
+                break;
+            }
+            if let Some(v) = v {
+                if !f(&k, &v) {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn page(&mut self, run: usize, page: usize) -> Result<Arc<Page>, Error> {
+        let run = &mut self.runs[run];
+        if let Some(p) = self.cache.get(&(run.id, page)) {
+            return Ok(Arc::clone(p));
+        }
+        let info = &run.pages[page];
+        let p = Arc::new(read_page(&mut run.file, info)?);
+        self.cache
+            .insert((run.id, page), Arc::clone(&p), info.length as usize);
+        Ok(p)
+    }
+
+    fn flush_if_full(&mut self) -> Result<(), Error> {
+        if self.buffer.len() < self.max_buffered {
+            return Ok(());
+        }
+        let buffer = std::mem::take(&mut self.buffer);
+        // removed keys only need to be kept while an older run may still have them
+        let drop_removed = self.runs.is_empty();
+        let run = self.write_run(buffer.into_iter().map(Ok), drop_removed)?;
+        self.runs.push(run);
+
+        while self.runs.len() >= 2 {
+            let n = self.runs.len();
+            if self.runs[n - 2].entries > self.runs[n - 1].entries * 2 {
+                break;
+            }
+            let newer = self.runs.pop().unwrap();
+            let older = self.runs.pop().unwrap();
+            let drop_removed = self.runs.is_empty();
+            let sources: Vec<Box<dyn Iterator<Item = Result<Entry, Error>>>> = vec![
+                Box::new(RunIter::new(&newer, Bound::Unbounded, false)?),
+                Box::new(RunIter::new(&older, Bound::Unbounded, false)?),
+            ];
+            let merged = self.write_run(MergeIter::new(sources, false), drop_removed)?;
+            fs::remove_file(&newer.path)?;
+            fs::remove_file(&older.path)?;
+            self.runs.push(merged);
+        }
+        Ok(())
+    }
+
+    fn write_run(
+        &mut self,
+        entries: impl Iterator<Item = Result<Entry, Error>>,
+        drop_removed: bool,
+    ) -> Result<Run, Error> {
+        let id = self.next_run_id;
+        self.next_run_id += 1;
+        let path = format!("{}/run-{}", self.dir, id);
+        let mut writer = BufWriter::new(File::create(&path)?);
+
+        let mut pages = Vec::new();
+        let mut count = 0;
+        let mut offset = 0;
+        let mut page = Vec::with_capacity(PAGE_SIZE);
+        let mut first_key = Vec::new();
+        for entry in entries {
+            let (k, v) = entry?;
+            if drop_removed && v.is_none() {
+                continue;
+            }
+            if page.is_empty() {
+                first_key = k.clone();
+            }
+            encode_entry(&mut page, &k, v.as_ref());
+            count += 1;
+            if page.len() >= PAGE_SIZE {
+                writer.write_all(&page)?;
+                pages.push(PageInfo {
+                    first_key: std::mem::take(&mut first_key),
+                    offset,
+                    length: page.len() as u64,
+                });
+                offset += page.len() as u64;
+                page.clear();
+            }
+        }
+        if !page.is_empty() {
+            writer.write_all(&page)?;
+            pages.push(PageInfo {
+                first_key,
+                offset,
+                length: page.len() as u64,
+            });
+        }
+        writer.flush()?;
+
+        Ok(Run {
+            id,
+            file: File::open(&path)?,
+            path,
+            entries: count,
+            pages,
+        })
+    }
+}
+
+// A dir for a new index inside `parent`, named after the current time
+pub(crate) fn new_index_dir(parent: &str) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    format!("{}/{}{}", parent, INDEX_DIR_PREFIX, nanos)
+}
+
+// Removes the indexes left in the data dir by a store that did not close
+pub(crate) fn remove_stale_indexes(data_dir: &str) -> Result<(), Error> {
+    for entry in fs::read_dir(data_dir)? {
+        let path = entry?.path();
+        let is_index = path
+            .file_name()
+            .and_then(|f| f.to_str())
+            .is_some_and(|f| f.starts_with(INDEX_DIR_PREFIX));
+        if is_index && path.is_dir() {
+            fs::remove_dir_all(path)?;
+        }
+    }
+    Ok(())
+}
+
+impl Drop for PagedIndex {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+// key length (4 bytes), key, and a flag: 0 for a removed key, 1 otherwise. Unless removed,
+// then file id, sequence number, offset and value length (or TOMBSTONE_LENGTH)
+fn encode_entry(buf: &mut Vec<u8>, key: &[u8], value: Option<&Key>) {
+    buf.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    buf.extend_from_slice(key);
+    let Some(v) = value else {
+        buf.push(0);
+        return;
+    };
+    buf.push(1);
+    let length = if v.is_tombstone() {
+        TOMBSTONE_LENGTH
+    } else {
+        v.length() as u32
+    };
+    buf.extend_from_slice(&v.file_id.to_be_bytes());
+    buf.extend_from_slice(&v.seq.to_be_bytes());
+    buf.extend_from_slice(&v.offset.to_be_bytes());
+    buf.extend_from_slice(&length.to_be_bytes());
+}
+
+fn read_page(file: &mut File, info: &PageInfo) -> Result<Page, Error> {
+    file.seek(SeekFrom::Start(info.offset))?;
+    let mut bytes = vec![0u8; info.length as usize];
+    file.read_exact(&mut bytes)?;
+    decode_page(&bytes)
+}
+
+fn decode_page(mut bytes: &[u8]) -> Result<Page, Error> {
+    let mut page = Vec::new();
+    while !bytes.is_empty() {
+        let k_len = read_u32(&mut bytes)? as usize;
+        let key = take(&mut bytes, k_len)?.to_vec();
+        let flag = take(&mut bytes, 1)?[0];
+        if flag == 0 {
+            page.push((key, None));
+            continue;
+        }
+        let key_info = Key::new(
+            read_u32(&mut bytes)?,
+            read_u64(&mut bytes)?,
+            read_u64(&mut bytes)?,
+            read_u32(&mut bytes)?,
+        );
+        page.push((key, Some(key_info)));
+    }
+    Ok(page)
+}
+
+fn take<'a>(bytes: &mut &'a [u8], n: usize) -> Result<&'a [u8], Error> {
+    if bytes.len() < n {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Key index page is incomplete",
+        ));
+    }
+    let (taken, rest) = bytes.split_at(n);
+    *bytes = rest;
+    Ok(taken)
+}
+
+fn read_u32(bytes: &mut &[u8]) -> Result<u32, Error> {
+    Ok(u32::from_be_bytes(take(bytes, 4)?.try_into().unwrap()))
+}
+
+fn read_u64(bytes: &mut &[u8]) -> Result<u64, Error> {
+    Ok(u64::from_be_bytes(take(bytes, 8)?.try_into().unwrap()))
+}
+
+// Entries of a run, from the first one at or after `from`, or in reverse from the last one at
+// or before it. Reads the pages in order without going through the cache
+struct RunIter {
+    file: File,
+    // in the order they are read
+    pages: Vec<PageInfo>,
+    next_page: usize,
+    entries: VecDeque<Entry>,
+    from: Bound<Vec<u8>>,
+    reverse: bool,
+}
+
+impl RunIter {
+    fn new(run: &Run, from: Bound<&[u8]>, reverse: bool) -> Result<Self, Error> {
+        // the page `from` falls in
+        let page = match from {
+            Bound::Included(s) | Bound::Excluded(s) => run
+                .pages
+                .partition_point(|p| p.first_key.as_slice() <= s)
+                .saturating_sub(1),
+            Bound::Unbounded if reverse => run.pages.len().saturating_sub(1),
+            Bound::Unbounded => 0,
+        };
+        let pages = if reverse {
+            run.pages[..min(page + 1, run.pages.len())]
+                .iter()
+                .rev()
+                .cloned()
+                .collect()
+        } else {
+            run.pages[page..].to_vec()
+        };
+        Ok(RunIter {
+            file: File::open(&run.path)?,
+            pages,
+            next_page: 0,
+            entries: VecDeque::new(),
+            from: from.map(|s| s.to_vec()),
+            reverse,
+        })
+    }
+}
+
+impl Iterator for RunIter {
+    type Item = Result<Entry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = if self.reverse {
+                self.entries.pop_back()
+            } else {
+                self.entries.pop_front()
+            };
+            if let Some((k, v)) = entry {
+                let before_from = match (&self.from, self.reverse) {
+                    (Bound::Included(s), false) => k < *s,
+                    (Bound::Excluded(s), false) => k <= *s,
+                    (Bound::Included(s), true) => k > *s,
+                    (Bound::Excluded(s), true) => k >= *s,
+                    (Bound::Unbounded, _) => false,
+                };
+                if before_from {
+                    continue;
+                }
+                return Some(Ok((k, v)));
+            }
+            let info = self.pages.get(self.next_page)?;
+            self.next_page += 1;
+            match read_page(&mut self.file, info) {
+                Ok(page) => self.entries.extend(page),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+// Merges sorted sources into one sorted sequence, or sources in reverse order into one in
+// reverse order. When a key is in more than one source, the entry of the first source wins
+struct MergeIter<'a> {
+    sources: Vec<Box<dyn Iterator<Item = Result<Entry, Error>> + 'a>>,
+    heads: Vec<Option<Entry>>,
+    started: bool,
+    reverse: bool,
+}
+
+impl<'a> MergeIter<'a> {
+    fn new(
+        sources: Vec<Box<dyn Iterator<Item = Result<Entry, Error>> + 'a>>,
+        reverse: bool,
+    ) -> Self {
+        let heads = sources.iter().map(|_| None).collect();
+        MergeIter {
+            sources,
+            heads,
+            started: false,
+            reverse,
+        }
+    }
+
+    fn refill(&mut self, i: usize) -> Result<(), Error> {
+        self.heads[i] = self.sources[i].next().transpose()?;
+        Ok(())
+    }
+}
+
+impl Iterator for MergeIter<'_> {
+    type Item = Result<Entry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.started {
+            self.started = true;
+            for i in 0..self.sources.len() {
+                if let Err(e) = self.refill(i) {
+                    return Some(Err(e));
+                }
+            }
+        }
+
+        // the smallest key, or the largest one in reverse
+        let mut first: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            let Some((k, _)) = head else {
+                continue;
+            };
+            let is_first = first.is_none_or(|m| {
+                let current = &self.heads[m].as_ref().unwrap().0;
+                if self.reverse {
+                    k > current
+                } else {
+                    k < current
+                }
+            });
+            if is_first {
+                first = Some(i);
+            }
+        }
+        let winner = self.heads[first?].take().unwrap();
+        for i in 0..self.heads.len() {
+            let same_key = self.heads[i].as_ref().is_some_and(|(k, _)| *k == winner.0);
+            if same_key || i == first.unwrap() {
+                if let Err(e) = self.refill(i) {
+                    return Some(Err(e));
+                }
+            }
+        }
+        Some(Ok(winner))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_info(n: u64) -> Key {
+        if n.is_multiple_of(11) {
+            Key::new_tombstone((n % 7) as u32, n, n * 10)
+        } else {
+            Key::new((n % 7) as u32, n, n * 10, n as u32)
+        }
+    }
+
+    fn collect(
+        index: &mut PagedIndex,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        reverse: bool,
+    ) -> Vec<Entry> {
+        let mut entries = Vec::new();
+        index
+            .scan(start, end, reverse, |k, v| {
+                entries.push((k.to_vec(), Some(*v)));
+                true
+            })
+            .unwrap();
+        entries
+    }
+
+    #[test]
+    fn test_paged_index() {
+        let dir = "test-data-paged-index";
+        // a small buffer, so that the keys go through several runs and merges
+        let mut index = PagedIndex::new(dir, 16 * PAGE_SIZE, 100).unwrap();
+        let mut expected: BTreeMap<Vec<u8>, Key> = BTreeMap::new();
+        for round in 0..3u64 {
+            for i in 0..2_000u64 {
+                let key = format!("key-{:05}", (i * 7919 + round) % 3_000).into_bytes();
+                let v = key_info(i + round * 10_000);
+                assert_eq!(
+                    expected.insert(key.clone(), v),
+                    index.insert(key, v).unwrap()
+                );
+            }
+            for i in (0..3_000u64).step_by(13 + round as usize) {
+                let key = format!("key-{:05}", i).into_bytes();
+                assert_eq!(expected.remove(&key), index.remove(&key).unwrap());
+            }
+        }
+        assert!(index.runs.len() > 1);
+        assert!(index.cache.size() <= 16 * PAGE_SIZE);
+
+        for i in 0..3_100u64 {
+            let key = format!("key-{:05}", i).into_bytes();
+            assert_eq!(expected.get(&key).cloned(), index.get(&key).unwrap());
+        }
+        let all: Vec<Entry> = expected
+            .iter()
+            .map(|(k, v)| (k.clone(), Some(*v)))
+            .collect();
+        assert_eq!(
+            all,
+            collect(&mut index, Bound::Unbounded, Bound::Unbounded, false)
+        );
+        let mut all_reversed = all.clone();
+        all_reversed.reverse();
+        assert_eq!(
+            all_reversed,
+            collect(&mut index, Bound::Unbounded, Bound::Unbounded, true)
+        );
+
+        let (start, end) = (b"key-00500".as_slice(), b"key-01200".as_slice());
+        let in_range: Vec<Entry> = expected
+            .range::<[u8], _>((Bound::Excluded(start), Bound::Included(end)))
+            .map(|(k, v)| (k.clone(), Some(*v)))
+            .collect();
+        assert_eq!(
+            in_range,
+            collect(
+                &mut index,
+                Bound::Excluded(start),
+                Bound::Included(end),
+                false
+            )
+        );
+        let mut in_range_reversed = in_range.clone();
+        in_range_reversed.reverse();
+        assert_eq!(
+            in_range_reversed,
+            collect(
+                &mut index,
+                Bound::Excluded(start),
+                Bound::Included(end),
+                true
+            )
+        );
+        let before_end: Vec<Entry> = expected
+            .range::<[u8], _>((Bound::Unbounded, Bound::Excluded(end)))
+            .rev()
+            .map(|(k, v)| (k.clone(), Some(*v)))
+            .collect();
+        assert_eq!(
+            before_end,
+            collect(&mut index, Bound::Unbounded, Bound::Excluded(end), true)
+        );
+
+        drop(index);
+        assert!(!std::path::Path::new(dir).exists());
+    }
+}
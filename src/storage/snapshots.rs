@@ -0,0 +1,103 @@
+use crate::storage::key_dir::Key;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex, Weak};
+
+// Keys changed since a snapshot was taken, with their entry at that time. `None` for a key
+// the snapshot did not have. Together with the unchanged keys of the key dir, it is the key
+// dir as it was when the snapshot was taken
+pub(crate) type Changes = Mutex<BTreeMap<Box<[u8]>, Option<Key>>>;
+
+// The open snapshots of a store, and the data files they keep from being deleted.
+// Locked after the key dir and the file table
+#[derive(Default)]
+pub(crate) struct Snapshots {
+    changes: Vec<Weak<Changes>>,
+    // how many open snapshots may read each file
+    pins: HashMap<u32, usize>,
+    // files a compaction is done with, deleted once no snapshot pins them
+    deferred: HashSet<u32>,
+}
+
+impl Snapshots {
+    // Registers a snapshot that may read the files
+    pub(crate) fn open(&mut self, file_ids: &[u32]) -> Arc<Changes> {
+        for id in file_ids {
+            *self.pins.entry(*id).or_default() += 1;
+        }
+        let changes = Arc::new(Mutex::new(BTreeMap::new()));
+        self.changes.push(Arc::downgrade(&changes));
+        changes
+    }
+
+    // Keeps the entry a key had before a change, for the snapshots that do not have it yet.
+    // Called with the key dir locked, before the change is seen
+    pub(crate) fn preserve(&mut self, key: &[u8], old: Option<Key>) {
+        self.changes.retain(|c| c.strong_count() > 0);
+        for changes in self.changes.iter().filter_map(|c| c.upgrade()) {
+            changes.lock().unwrap().entry(key.into()).or_insert(old);
+        }
+    }
+
+    pub(crate) fn is_pinned(&self, file_id: u32) -> bool {
+        self.pins.contains_key(&file_id)
+    }
+
+    pub(crate) fn defer_delete(&mut self, file_id: u32) {
+        self.deferred.insert(file_id);
+    }
+
+    // Unpins the files of a snapshot that was dropped. Returns the deferred files that no
+    // snapshot pins anymore, for the caller to delete
+    pub(crate) fn close(&mut self, file_ids: &[u32]) -> Vec<u32> {
+        for id in file_ids {
+            if let Some(count) = self.pins.get_mut(id) {
+                *count -= 1;
+                if *count == 0 {
+                    self.pins.remove(id);
+                }
+            }
+        }
+        let unpinned: Vec<u32> = self
+            .deferred
+            .iter()
+            .filter(|id| !self.pins.contains_key(id))
+            .copied()
+            .collect();
+        for id in &unpinned {
+            self.deferred.remove(id);
+        }
+        unpinned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshots() {
+        let mut snapshots = Snapshots::default();
+        let first = snapshots.open(&[1, 2]);
+        snapshots.preserve(b"a", None);
+        let second = snapshots.open(&[2]);
+        snapshots.preserve(b"a", Some(Key::new(1, 5, 0, 3)));
+        snapshots.preserve(b"b", Some(Key::new(2, 6, 0, 3)));
+
+        // each snapshot keeps the entry from before its first change
+        assert_eq!(Some(&None), first.lock().unwrap().get(&b"a"[..]));
+        let second_a = second.lock().unwrap().get(&b"a"[..]).copied();
+        assert_eq!(Some(Some(Key::new(1, 5, 0, 3))), second_a);
+        assert_eq!(2, first.lock().unwrap().len());
+
+        snapshots.defer_delete(1);
+        snapshots.defer_delete(2);
+        assert!(snapshots.is_pinned(1));
+        drop(first);
+        assert_eq!(vec![1], snapshots.close(&[1, 2]));
+        assert!(!snapshots.is_pinned(1));
+        assert_eq!(vec![2], snapshots.close(&[2]));
+        drop(second);
+        snapshots.preserve(b"c", None);
+        assert!(snapshots.changes.is_empty());
+    }
+}
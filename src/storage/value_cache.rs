@@ -0,0 +1,101 @@
+use crate::storage::bit_cask::CacheStats;
+use crate::storage::data_files::is_expired;
+use crate::storage::lru::Lru;
+use std::sync::Mutex;
+
+// sequence number and expiry of the record the value was read from, and the value
+type SequencedValue = (u64, u64, Vec<u8>);
+
+// Values of recently read keys, bounded by the bytes of their keys and values.
+// Every value is kept with the sequence number of its record, and is only returned for that
+// number. A compaction copies records with their numbers, so cached values stay valid when
+// keys are relocated, while a write gives the key a new number
+pub(crate) struct ValueCache {
+    values: Mutex<Lru<Vec<u8>, SequencedValue>>,
+    stats: Mutex<CacheStats>,
+}
+
+impl ValueCache {
+    pub(crate) fn new(capacity_bytes: usize) -> Self {
+        ValueCache {
+            values: Mutex::new(Lru::new(capacity_bytes)),
+            stats: Default::default(),
+        }
+    }
+
+    // The value of the key, if it is cached for the record with the sequence number and has
+    // not expired by `now`
+    pub(crate) fn get(&self, key: &[u8], seq: u64, now: u64) -> Option<Vec<u8>> {
+        let value = {
+            let mut values = self.values.lock().unwrap();
+            match values.get(key) {
+                Some((cached_seq, expires_at, value))
+                    if *cached_seq == seq && !is_expired(*expires_at, now) =>
+                {
+                    Some(value.clone())
+                }
+                _ => None,
+            }
+        };
+        let mut stats = self.stats.lock().unwrap();
+        match value {
+            Some(_) => stats.hits += 1,
+            None => stats.misses += 1,
+        }
+        value
+    }
+
+    pub(crate) fn insert(&self, key: Vec<u8>, seq: u64, expires_at: u64, value: Vec<u8>) {
+        let size = key.len() + value.len();
+        let evicted = self
+            .values
+            .lock()
+            .unwrap()
+            .insert(key, (seq, expires_at, value), size);
+        self.stats.lock().unwrap().evictions += evicted as u64;
+    }
+
+    // Drops the value of a key that was written or deleted
+    pub(crate) fn remove(&self, key: &[u8]) {
+        self.values.lock().unwrap().remove(key);
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        let values = self.values.lock().unwrap();
+        CacheStats {
+            bytes: values.size() as u64,
+            entries: values.len() as u64,
+            ..*self.stats.lock().unwrap()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_value_cache() {
+        let cache = ValueCache::new(20);
+        assert_eq!(None, cache.get(b"k1", 1, 0));
+        cache.insert(b"k1".to_vec(), 1, 0, b"value-1".to_vec());
+        assert_eq!(Some(b"value-1".to_vec()), cache.get(b"k1", 1, 0));
+        // a newer record of the key
+        assert_eq!(None, cache.get(b"k1", 2, 0));
+
+        cache.insert(b"k2".to_vec(), 3, 0, b"value-2".to_vec());
+        cache.insert(b"k3".to_vec(), 4, 1000, b"value-3".to_vec());
+        assert_eq!(None, cache.get(b"k1", 1, 0));
+        cache.remove(b"k2");
+        assert_eq!(None, cache.get(b"k2", 3, 0));
+        assert_eq!(Some(b"value-3".to_vec()), cache.get(b"k3", 4, 999));
+        assert_eq!(None, cache.get(b"k3", 4, 1000));
+
+        let stats = cache.stats();
+        assert_eq!(2, stats.hits);
+        assert_eq!(5, stats.misses);
+        assert_eq!(1, stats.evictions);
+        assert_eq!(1, stats.entries);
+        assert_eq!(9, stats.bytes);
+    }
+}
@@ -0,0 +1,95 @@
+// Memory taken by the key dir for each key. The counting allocator replaces the global
+// allocator, so the measure lives in its own test binary
+use key_value_storage::storage::bit_cask::{open, BitCaskOptions};
+use key_value_storage::storage::KVStorage;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fs;
+
+// Counts the bytes allocated by each thread, so that the store's background threads do not
+// get in the way of the measure
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATED: Cell<isize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATED.try_with(|a| a.set(a.get() + layout.size() as isize));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let _ = ALLOCATED.try_with(|a| a.set(a.get() - layout.size() as isize));
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn allocated() -> isize {
+    ALLOCATED.with(|a| a.get())
+}
+
+// How entries were kept before file ids
+#[allow(dead_code)]
+struct FilenameKey {
+    filename: String,
+    timestamp: u64,
+    offset: u64,
+    length: usize,
+    tombstone: bool,
+}
+
+const KEYS: u64 = 100_000;
+
+#[test]
+fn key_memory_test() {
+    let filenames: Vec<String> = (0..10)
+        .map(|i| format!("data/data-file17000000000000000{:02}", i))
+        .collect();
+
+    let before = allocated();
+    let mut old: BTreeMap<Vec<u8>, FilenameKey> = BTreeMap::new();
+    for i in 0..KEYS {
+        let key_info = FilenameKey {
+            filename: filenames[(i % 10) as usize].clone(),
+            timestamp: i,
+            offset: i * 100,
+            length: 100,
+            tombstone: false,
+        };
+        old.insert(i.to_be_bytes().to_vec(), key_info);
+    }
+    let old_per_key = (allocated() - before) / KEYS as isize;
+    drop(old);
+
+    let dir = "test-data-key-memory";
+    let _ = fs::remove_dir_all(dir);
+    let mut bit_cask = open(dir, BitCaskOptions::new().background_merge(false)).unwrap();
+    bit_cask.put(vec![0; 8], vec![0; 100]).unwrap();
+    let before = allocated();
+    for i in 1..KEYS {
+        bit_cask
+            .put(i.to_be_bytes().to_vec(), vec![0; 100])
+            .unwrap();
+    }
+    let per_key = (allocated() - before) / KEYS as isize;
+
+    // the keys alone, in a map, take the same memory in both layouts
+    let before = allocated();
+    let mut keys = BTreeMap::new();
+    for i in 0..KEYS {
+        keys.insert(i.to_be_bytes().to_vec(), ());
+    }
+    let per_bare_key = (allocated() - before) / KEYS as isize;
+    drop(keys);
+    // what an entry adds to its key is cut by more than 3 times
+    assert!((per_key - per_bare_key) * 3 <= old_per_key - per_bare_key);
+
+    bit_cask.close().unwrap();
+    fs::remove_dir_all(dir).unwrap();
+}